@@ -4,29 +4,82 @@ const BUILD_DIR: &str = ".flatplay";
 
 pub struct BuildDirs {
     pub base: PathBuf,
+    pub arch: Option<String>,
+    custom_build_dir: Option<PathBuf>,
+    custom_repo_dir: Option<PathBuf>,
 }
 
 impl BuildDirs {
-    pub fn new(base: PathBuf) -> Self {
-        Self { base }
+    pub fn new(
+        base: PathBuf,
+        custom_build_dir: Option<PathBuf>,
+        custom_repo_dir: Option<PathBuf>,
+        arch: Option<String>,
+    ) -> Self {
+        Self {
+            base,
+            arch,
+            custom_build_dir,
+            custom_repo_dir,
+        }
+    }
+    /// Joins `name` to the build dir, suffixed with the target arch if one is set.
+    fn arch_dir(&self, name: &str) -> PathBuf {
+        match &self.arch {
+            Some(arch) => self.build_dir().join(format!("{name}-{arch}")),
+            None => self.build_dir().join(name),
+        }
     }
     pub fn build_dir(&self) -> PathBuf {
-        self.base.join(BUILD_DIR)
+        match &self.custom_build_dir {
+            Some(dir) => self.base.join(dir),
+            None => self.base.join(BUILD_DIR),
+        }
     }
+    /// Returns the repo directory, which can live outside the build dir when overridden.
+    /// An overridden repo dir is used as is, without an arch suffix.
     pub fn repo_dir(&self) -> PathBuf {
-        self.build_dir().join("repo")
+        match &self.custom_repo_dir {
+            Some(dir) => self.base.join(dir),
+            None => self.arch_dir("repo"),
+        }
+    }
+    /// Returns true if the repo dir was overridden and lives outside the build dir.
+    pub fn is_repo_dir_external(&self) -> bool {
+        self.custom_repo_dir.is_some() && !self.repo_dir().starts_with(self.build_dir())
     }
     pub fn build_subdir(&self) -> PathBuf {
-        self.build_dir().join("_build")
+        self.arch_dir("_build")
     }
     pub fn flatpak_builder_dir(&self) -> PathBuf {
-        self.build_dir().join("flatpak-builder")
+        self.arch_dir("flatpak-builder")
+    }
+    /// Returns the dir independent modules are built in, each in a subdir with its own
+    /// flatpak-builder state, before being copied into the repo dir.
+    pub fn independent_modules_dir(&self) -> PathBuf {
+        self.arch_dir("independent")
+    }
+    /// Returns the scratch build dir `checkout` builds into, so the app repo is left alone.
+    pub fn checkout_dir(&self) -> PathBuf {
+        self.arch_dir("checkout")
     }
     pub fn finalized_repo_dir(&self) -> PathBuf {
-        self.build_dir().join("finalized-repo")
+        self.arch_dir("finalized-repo")
     }
     pub fn ostree_dir(&self) -> PathBuf {
-        self.build_dir().join("ostree")
+        self.arch_dir("ostree")
+    }
+    /// Returns the data dir used by `run --clean-data`.
+    pub fn clean_data_dir(&self) -> PathBuf {
+        self.build_dir().join("clean-data")
+    }
+    /// Returns the file `--trace` logs commands to. `clean` keeps it.
+    pub fn trace_file(&self) -> PathBuf {
+        self.build_dir().join("trace.log")
+    }
+    /// Returns the dir `run --profile` writes its captures to.
+    pub fn profiles_dir(&self) -> PathBuf {
+        self.build_dir().join("profiles")
     }
     pub fn metadata_file(&self) -> PathBuf {
         self.repo_dir().join("metadata")
@@ -34,7 +87,58 @@ impl BuildDirs {
     pub fn files_dir(&self) -> PathBuf {
         self.repo_dir().join("files")
     }
+    /// Returns the dir runtimes are built into, in place of `files`.
+    pub fn usr_dir(&self) -> PathBuf {
+        self.repo_dir().join("usr")
+    }
     pub fn var_dir(&self) -> PathBuf {
         self.repo_dir().join("var")
     }
+    /// Returns true if the repo has everything `build-init` creates.
+    // From gnome-builder: https://gitlab.gnome.org/GNOME/gnome-builder/-/blob/8579055f5047a0af5462e8a587b0742014d71d64/src/plugins/flatpak/gbp-flatpak-pipeline-addin.c#L220
+    pub fn is_repo_initialized(&self) -> bool {
+        self.metadata_file().is_file()
+            && (self.files_dir().is_dir() || self.usr_dir().is_dir())
+            && self.var_dir().is_dir()
+    }
+    /// Returns the entries `build-init` creates in the repo dir.
+    pub fn repo_init_entries(&self) -> [PathBuf; 4] {
+        [
+            self.metadata_file(),
+            self.files_dir(),
+            self.usr_dir(),
+            self.var_dir(),
+        ]
+    }
+    /// Returns true if the repo has only part of what `build-init` creates, as an interrupted
+    /// `build-init` leaves it.
+    pub fn is_repo_partial(&self) -> bool {
+        let any_part = self.repo_init_entries().iter().any(|entry| entry.exists());
+        any_part && !self.is_repo_initialized()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_repo_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dirs = BuildDirs::new(dir.path().to_path_buf(), None, None, None);
+        assert!(!build_dirs.is_repo_partial());
+
+        // An empty repo dir, like an overridden one, is just uninitialized.
+        std::fs::create_dir_all(build_dirs.repo_dir()).unwrap();
+        assert!(!build_dirs.is_repo_partial());
+
+        std::fs::create_dir_all(build_dirs.files_dir()).unwrap();
+        std::fs::create_dir_all(build_dirs.var_dir()).unwrap();
+        assert!(build_dirs.is_repo_partial());
+        assert!(!build_dirs.is_repo_initialized());
+
+        std::fs::write(build_dirs.metadata_file(), "[Application]\n").unwrap();
+        assert!(!build_dirs.is_repo_partial());
+        assert!(build_dirs.is_repo_initialized());
+    }
 }
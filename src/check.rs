@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use colored::*;
+
+use crate::command::{HostRunner, set_flatpak_bin};
+use crate::config::Config;
+use crate::error::Error;
+use crate::manifest::{Manifest, find_manifests};
+use crate::runtimes::missing_runtimes;
+use crate::state::State;
+use crate::utils::installation_arg;
+
+/// Picks the manifest to check: `path` if given, then the active one, the configured one,
+/// and finally the first one found, like a build would.
+fn manifest_to_check(state: &State, config: &Config, path: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = path {
+        return Ok(std::path::absolute(path)?);
+    }
+    if let Some(path) = state.active_manifest.as_ref().filter(|path| path.exists()) {
+        return Ok(path.clone());
+    }
+    if let Some(path) = &config.manifest {
+        return Ok(state.base_dir.join(path));
+    }
+    let current_dir = std::env::current_dir()?;
+    find_manifests(&current_dir, &state.base_dir, &config.manifest_ignore)?
+        .into_iter()
+        .next()
+        .map(|(path, _)| path)
+        .ok_or_else(|| Error::NoManifest.into())
+}
+
+/// Validates a manifest without building it, printing every problem found.
+pub fn check(state: &State, config: &Config, path: Option<&Path>) -> Result<()> {
+    set_flatpak_bin(config.flatpak_bin.clone());
+    let manifest_path = manifest_to_check(state, config, path)?;
+    println!("{} {:?}", "Checking manifest:".bold(), manifest_path);
+    let (manifest, mut problems) = Manifest::check_file(&manifest_path)?;
+    let installation = config.installation.as_deref().map(installation_arg);
+    problems.extend(
+        missing_runtimes(&manifest, installation.as_deref(), &HostRunner)
+            .into_iter()
+            .map(|ref_| format!("Runtime {ref_} is not installed")),
+    );
+
+    if problems.is_empty() {
+        println!("{} The manifest is valid.", "✔".green());
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{} {}", "✘".red(), problem);
+    }
+    Err(anyhow!(
+        "Found {} problem(s) in the manifest",
+        problems.len()
+    ))
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use walkdir::WalkDir;
+
+/// Matches `text` against a shell-style glob where `*` and `?` don't match `/`.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            // Try every split point up to the next path separator.
+            (0..=text.len())
+                .take_while(|&i| i == 0 || text[i - 1] != '/')
+                .any(|i| glob_match_chars(&pattern[1..], &text[i..]))
+        }
+        Some('?') => {
+            !text.is_empty() && text[0] != '/' && glob_match_chars(&pattern[1..], &text[1..])
+        }
+        Some(c) => !text.is_empty() && text[0] == *c && glob_match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Returns true if the entry at `relative_path` matches the cleanup `pattern`.
+/// Like flatpak-builder, patterns starting with `/` match from the root of the prefix,
+/// while other patterns match file names anywhere in the tree.
+fn matches_cleanup_pattern(pattern: &str, relative_path: &Path) -> bool {
+    if let Some(pattern) = pattern.strip_prefix('/') {
+        relative_path
+            .to_str()
+            .is_some_and(|path| glob_match(pattern.trim_end_matches('/'), path))
+    } else {
+        relative_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| glob_match(pattern, name))
+    }
+}
+
+/// Removes everything under `files_dir` matching the manifest `cleanup` patterns.
+/// Returns the removed paths.
+pub fn apply_cleanup(files_dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut removed: Vec<PathBuf> = Vec::new();
+    if patterns.is_empty() || !files_dir.is_dir() {
+        return Ok(removed);
+    }
+
+    let mut walker = WalkDir::new(files_dir).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(files_dir)?;
+        if !patterns
+            .iter()
+            .any(|pattern| matches_cleanup_pattern(pattern, relative_path))
+        {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            fs::remove_dir_all(entry.path())?;
+            walker.skip_current_dir();
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        removed.push(entry.into_path());
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("*.la", "libfoo.la"));
+        assert!(!glob_match("*.la", "libfoo.so"));
+        assert!(glob_match("lib/*.a", "lib/libfoo.a"));
+        assert!(!glob_match("*.a", "lib/libfoo.a"));
+        assert!(glob_match("bin/fo?", "bin/foo"));
+        assert!(glob_match("include", "include"));
+    }
+
+    #[test]
+    fn cleanup_removes_matching_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let files_dir = dir.path();
+        for path in [
+            "bin/foo",
+            "bin/app",
+            "include/foo.h",
+            "lib/libfoo.la",
+            "lib/libfoo.a",
+            "lib/libfoo.so",
+        ] {
+            let path = files_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let patterns: Vec<String> = ["/bin/foo", "/include", "*.la", "*.a"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        apply_cleanup(files_dir, &patterns).unwrap();
+
+        assert!(!files_dir.join("bin/foo").exists());
+        assert!(files_dir.join("bin/app").exists());
+        assert!(!files_dir.join("include").exists());
+        assert!(!files_dir.join("lib/libfoo.la").exists());
+        assert!(!files_dir.join("lib/libfoo.a").exists());
+        assert!(files_dir.join("lib/libfoo.so").exists());
+    }
+}
@@ -1,101 +1,800 @@
-use std::process::{Command, Stdio};
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use colored::*;
+use nix::pty::{Winsize, openpty};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, killpg, sigaction};
+use nix::unistd::Pid;
+
+use crate::cleanup::glob_match;
+use crate::error::Error;
+use crate::utils::{format_duration, format_timestamp};
+
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const TIMEOUT_KILL_GRACE: Duration = Duration::from_secs(5);
+// Process group of the running command with a timeout, or 0 when there is none.
+static TIMED_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// How long a command must stay silent before the progress spinner shows up.
+const SPINNER_DELAY: Duration = Duration::from_secs(3);
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+/// Clears the current terminal line.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// Env var name patterns whose values are masked when echoing commands.
+const DEFAULT_REDACTED_ENV: [&str; 3] = ["*TOKEN*", "*SECRET*", "*PASSWORD*"];
+
+static EXTRA_REDACTED_ENV: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Env var naming the flatpak binary to use, taking precedence over the config file.
+const FLATPAK_BIN_ENV: &str = "FLATPLAY_FLATPAK_BIN";
+
+static FLATPAK_BIN: OnceLock<String> = OnceLock::new();
+
+static TRACE_FILE: OnceLock<std::path::PathBuf> = OnceLock::new();
+
+static FLATPAK_VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Makes flatpak and flatpak-builder log verbosely, for the commands run through `HostRunner`.
+pub fn set_flatpak_verbose(verbose: bool) {
+    FLATPAK_VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+// Puts the `-v` flag flatpak and flatpak-builder both take in front of `args` if `verbose`.
+fn verbose_args<'a>(args: &[&'a str], verbose: bool) -> Vec<&'a str> {
+    let verbose = verbose.then_some("-v");
+    verbose.into_iter().chain(args.iter().copied()).collect()
+}
+
+fn flatpak_verbose() -> bool {
+    FLATPAK_VERBOSE.load(Ordering::Relaxed)
+}
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Makes flatplay note how it runs commands, like which flatpak-builder it picked.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+// Prints a dimmed note when `--verbose` is set.
+fn verbose_note(note: &str) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{}", note.dimmed());
+    }
+}
+
+// Starts appending a line for every spawned command to `path`. Only the first call has an effect.
+pub fn set_trace_file(path: std::path::PathBuf) {
+    let _ = TRACE_FILE.set(path);
+}
+
+// Appends a timestamped line to the trace file, if tracing is enabled.
+// Tracing must never break a build, so write errors are ignored.
+fn trace(line: &str) {
+    let Some(path) = TRACE_FILE.get() else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Ok(mut file) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+    {
+        let _ = writeln!(
+            file,
+            "{} {}",
+            format_timestamp(std::time::SystemTime::now()),
+            line
+        );
+    }
+}
+
+// Describes how a command ended for the trace, as `exit=N`, `signal=N` or `error="..."`.
+fn trace_outcome(result: &std::io::Result<ExitStatus>) -> String {
+    use std::os::unix::process::ExitStatusExt;
+
+    match result {
+        Ok(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("exit={code}"),
+            (None, Some(signal)) => format!("signal={signal}"),
+            (None, None) => "exit=?".to_string(),
+        },
+        Err(e) => format!("error={:?}", e.to_string()),
+    }
+}
+
+// Sets the flatpak binary configured in the config file, unless `FLATPLAY_FLATPAK_BIN` is set.
+// Only the first call has an effect, and only before `flatpak_bin` is first used.
+pub fn set_flatpak_bin(configured: Option<String>) {
+    let _ = FLATPAK_BIN.set(resolve_flatpak_bin(
+        std::env::var(FLATPAK_BIN_ENV).ok(),
+        configured,
+    ));
+}
+
+fn resolve_flatpak_bin(from_env: Option<String>, configured: Option<String>) -> String {
+    from_env
+        .filter(|bin| !bin.is_empty())
+        .or(configured)
+        .unwrap_or_else(|| "flatpak".to_string())
+}
+
+// Returns the flatpak binary used for every flatpak invocation.
+pub fn flatpak_bin() -> &'static str {
+    FLATPAK_BIN.get_or_init(|| resolve_flatpak_bin(std::env::var(FLATPAK_BIN_ENV).ok(), None))
+}
+
+// Adds env var name patterns to mask when echoing commands, on top of the defaults.
+// Only the first call has an effect.
+pub fn set_redacted_env(patterns: Vec<String>) {
+    let _ = EXTRA_REDACTED_ENV.set(patterns);
+}
+
+// Masks the value of `--env=KEY=VALUE` arguments whose key matches one of `patterns`,
+// ignoring case. Other arguments are returned as is.
+fn redact_arg<'a>(arg: &'a str, patterns: &[&str]) -> std::borrow::Cow<'a, str> {
+    let Some((key, _)) = arg
+        .strip_prefix("--env=")
+        .and_then(|env| env.split_once('='))
+    else {
+        return arg.into();
+    };
+    let upper_key = key.to_uppercase();
+    if patterns
+        .iter()
+        .any(|pattern| glob_match(&pattern.to_uppercase(), &upper_key))
+    {
+        format!("--env={key}=***").into()
+    } else {
+        arg.into()
+    }
+}
+
+// Returns the command line to echo, with secret env values masked.
+fn display_args(args: &[&str]) -> String {
+    let patterns: Vec<&str> = DEFAULT_REDACTED_ENV
+        .into_iter()
+        .chain(
+            EXTRA_REDACTED_ENV
+                .get()
+                .into_iter()
+                .flatten()
+                .map(|s| s.as_str()),
+        )
+        .collect();
+    args.iter()
+        .map(|arg| redact_arg(arg, &patterns))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
 // Returns true if running inside a Flatpak sandbox.
 fn is_sandboxed() -> bool {
     std::path::Path::new("/.flatpak-info").exists()
 }
 
-// Returns true if running inside a container like Toolbx or distrobox.
+// Returns true if running inside a container like Toolbx, distrobox or Docker.
 fn is_inside_container() -> bool {
-    std::path::Path::new("/run/.containerenv").exists()
+    detect_container(
+        std::path::Path::new("/"),
+        std::env::var("container").ok().as_deref(),
+    )
+}
+
+// Detects a container from the marker files under `root` and the `container` env var.
+// Flatpak also sets `container`, but its sandbox is handled separately by host spawning.
+fn detect_container(root: &std::path::Path, container_env: Option<&str>) -> bool {
+    root.join("run/.containerenv").exists()
+        || root.join(".dockerenv").exists()
+        || container_env.is_some_and(|value| !value.is_empty() && value != "flatpak")
 }
 
 // Returns true if the given command with arguments executes successfully.
 fn command_succeeds(cmd: &str, args: &[&str]) -> bool {
-    Command::new(cmd)
+    let start = Instant::now();
+    let result = Command::new(cmd)
         .args(args)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .status()
-        .is_ok_and(|s| s.success())
+        .status();
+    trace(&format!(
+        "PROBE {} duration={:.3}s {} {}",
+        trace_outcome(&result),
+        start.elapsed().as_secs_f64(),
+        cmd,
+        display_args(args)
+    ));
+    result.is_ok_and(|s| s.success())
+}
+
+// Rewrites a command so it runs on the host when we are inside a Flatpak sandbox.
+fn host_command<'a>(command: &'a str, args: Vec<&'a str>) -> (&'a str, Vec<&'a str>) {
+    if !is_sandboxed() {
+        return (command, args);
+    }
+    if command_succeeds("host-spawn", &["--version"]) {
+        let mut new_args = vec![command];
+        new_args.extend_from_slice(&args);
+        ("host-spawn", new_args)
+    } else {
+        let mut new_args = vec![
+            "--host",
+            "--watch-bus",
+            "--env=TERM=xterm-256color",
+            command,
+        ];
+        new_args.extend_from_slice(&args);
+        ("flatpak-spawn", new_args)
+    }
+}
+
+// Names how `host_command` spawns `command` as `program`: directly or through a host spawner.
+fn spawn_strategy(command: &str, program: &str) -> &'static str {
+    match program {
+        _ if program == command => "native",
+        "host-spawn" => "host-spawn",
+        _ => "flatpak-spawn",
+    }
+}
+
+// Returns true if the given command executes successfully on the host.
+pub fn host_command_succeeds(command: &str, args: &[&str]) -> bool {
+    let (program, args) = host_command(command, args.to_vec());
+    command_succeeds(program, &args)
+}
+
+// Returns the stdout of the given command run on the host, if it executes successfully.
+pub fn host_command_output(command: &str, args: &[&str]) -> Option<String> {
+    let (program, args) = host_command(command, args.to_vec());
+    let output = Command::new(program)
+        .args(&args)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Forwards a signal to the process group of the running command with a timeout, which doesn't
+// get the terminal's Ctrl-C or `flatplay stop` on its own, then dies of it as by default.
+extern "C" fn forward_signal(signal: nix::libc::c_int) {
+    let group = TIMED_GROUP.load(Ordering::SeqCst);
+    // SAFETY: only async-signal-safe functions are called.
+    unsafe {
+        if group > 0 {
+            nix::libc::killpg(group, signal);
+        }
+        nix::libc::signal(signal, nix::libc::SIG_DFL);
+        nix::libc::raise(signal);
+    }
+}
+
+fn forward_signals_to_timed_group() {
+    static INSTALLED: std::sync::Once = std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        let action = SigAction::new(
+            SigHandler::Handler(forward_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for signal in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+            // SAFETY: the handler only calls async-signal-safe functions.
+            let _ = unsafe { sigaction(signal, &action) };
+        }
+    });
+}
+
+// Waits for the child to exit. If it outlives the timeout, its process group is asked to
+// terminate and killed after a grace period, so whatever it spawned, like the compilers of a
+// build, goes too. Children with a timeout are spawned in a process group of their own.
+fn wait_with_timeout(child: &mut Child, timeout: Option<Duration>) -> Result<ExitStatus> {
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?);
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+
+    let group = Pid::from_raw(child.id() as i32);
+    let _ = killpg(group, Signal::SIGTERM);
+    let grace_start = Instant::now();
+    while grace_start.elapsed() < TIMEOUT_KILL_GRACE {
+        if child.try_wait()?.is_some() {
+            break;
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+    let _ = killpg(group, Signal::SIGKILL);
+    let _ = child.wait();
+
+    Err(anyhow::anyhow!(
+        "Command timed out after {} seconds",
+        timeout.as_secs()
+    ))
+}
+
+/// Output bookkeeping shared by the output forwarders and the spinner.
+struct Progress {
+    last_output: Instant,
+    /// Whether the last output ended a line, so the spinner can't clobber a partial one.
+    at_line_start: bool,
+    spinner_shown: bool,
+}
+
+// Copies a child pipe to our own output, clearing the spinner before each write.
+fn forward_output(
+    mut pipe: impl Read,
+    mut out: impl Write,
+    progress: &Mutex<Progress>,
+) -> std::io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = pipe.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        let mut progress = progress.lock().unwrap();
+        if progress.spinner_shown {
+            eprint!("{CLEAR_LINE}");
+            progress.spinner_shown = false;
+        }
+        out.write_all(&buffer[..read])?;
+        out.flush()?;
+        progress.last_output = Instant::now();
+        progress.at_line_start = buffer[read - 1] == b'\n';
+    }
+}
+
+// Draws a spinner with the elapsed time on stderr while the command is silent, until `done`.
+fn show_spinner(progress: &Mutex<Progress>, done: &AtomicBool) {
+    let start = Instant::now();
+    let mut frame = 0;
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+        let mut progress = progress.lock().unwrap();
+        if !progress.at_line_start || progress.last_output.elapsed() < SPINNER_DELAY {
+            continue;
+        }
+        eprint!(
+            "{CLEAR_LINE}{} {}",
+            SPINNER_FRAMES[frame % SPINNER_FRAMES.len()]
+                .to_string()
+                .purple(),
+            format!("Still working ({})", format_duration(start.elapsed())).dimmed()
+        );
+        let _ = std::io::stderr().flush();
+        progress.spinner_shown = true;
+        frame += 1;
+    }
+    if progress.lock().unwrap().spinner_shown {
+        eprint!("{CLEAR_LINE}");
+    }
+}
+
+// Waits for a child writing its stdout and stderr to a pseudo-terminal, forwarding what it
+// writes from `output` and showing a spinner whenever it stays silent for a while.
+fn wait_with_spinner(
+    child: &mut Child,
+    output: File,
+    timeout: Option<Duration>,
+) -> Result<ExitStatus> {
+    let progress = Arc::new(Mutex::new(Progress {
+        last_output: Instant::now(),
+        at_line_start: true,
+        spinner_shown: false,
+    }));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let forwarder = {
+        let progress = Arc::clone(&progress);
+        thread::spawn(move || forward_output(output, std::io::stdout(), &progress))
+    };
+    let spinner = {
+        let progress = Arc::clone(&progress);
+        let done = Arc::clone(&done);
+        thread::spawn(move || show_spinner(&progress, &done))
+    };
+
+    let status = wait_with_timeout(child, timeout);
+    // The output ends once the child and anything it spawned exit. After a timeout its
+    // children may still hold the terminal, so the forwarder is left behind rather than waited on.
+    if status.is_ok() {
+        let _ = forwarder.join();
+    }
+    done.store(true, Ordering::Relaxed);
+    let _ = spinner.join();
+    status
 }
 
-// Runs a command, handling Flatpak sandbox and container specifics.
-pub fn run_command(
+// Returns the size of the terminal on stdout, to give the pseudo-terminal commands write to.
+fn terminal_size() -> Option<Winsize> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a winsize to the given pointer.
+    let result = unsafe { nix::libc::ioctl(1, nix::libc::TIOCGWINSZ, &mut size) };
+    (result == 0).then_some(size)
+}
+
+// Runs a command with the extra `env`, handling Flatpak sandbox and container specifics.
+// It is killed if it runs longer than `timeout`.
+pub fn run_command_with_timeout(
     command: &str,
     args: &[&str],
+    env: &[(String, String)],
     working_dir: Option<&std::path::Path>,
+    timeout: Option<Duration>,
 ) -> Result<()> {
-    let mut command_args = args.to_vec();
+    spawn_command(command, args, env, working_dir, timeout, false)
+}
 
-    // Workaround for rofiles-fuse issues in containers.
-    if command == "flatpak-builder"
-        && is_inside_container()
-        && !command_args.contains(&"--disable-rofiles-fuse")
-    {
-        command_args.push("--disable-rofiles-fuse");
-    }
+// Runs a long, possibly silent command like `run_command_with_timeout`, showing a spinner while
+// it produces no output. The spinner is only drawn when stdout and stderr are terminals.
+pub fn run_command_with_spinner(
+    command: &str,
+    args: &[&str],
+    env: &[(String, String)],
+    working_dir: Option<&std::path::Path>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let spinner = std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    spawn_command(command, args, env, working_dir, timeout, spinner)
+}
 
-    let (program, final_args) = if is_sandboxed() {
-        if command_succeeds("host-spawn", &["--version"]) {
-            let mut new_args = vec![command];
-            new_args.extend_from_slice(&command_args);
-            ("host-spawn", new_args)
-        } else {
-            let mut new_args = vec![
-                "--host",
-                "--watch-bus",
-                "--env=TERM=xterm-256color",
-                command,
-            ];
-            new_args.extend_from_slice(&command_args);
-            ("flatpak-spawn", new_args)
-        }
-    } else {
-        (command, command_args)
-    };
+fn spawn_command(
+    command: &str,
+    args: &[&str],
+    env: &[(String, String)],
+    working_dir: Option<&std::path::Path>,
+    timeout: Option<Duration>,
+    spinner: bool,
+) -> Result<()> {
+    let (program, final_args) = host_command(command, args.to_vec());
+    // The strategy only depends on the environment, so it is noted once.
+    static SPAWN_STRATEGY_NOTED: std::sync::Once = std::sync::Once::new();
+    SPAWN_STRATEGY_NOTED.call_once(|| {
+        verbose_note(&format!(
+            "Spawning commands with the {} strategy",
+            spawn_strategy(command, program)
+        ));
+    });
 
+    let shown_args = display_args(&final_args);
     println!(
         "\n{} {} {}",
         ">".purple().bold(),
         program.italic(),
-        final_args.join(" ").italic()
+        shown_args.italic()
     );
     let mut cmd = Command::new(program);
-    cmd.args(&final_args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
+    cmd.args(&final_args);
+    cmd.envs(env.iter().cloned());
+    // With the spinner, the child writes to a pseudo-terminal we forward, so we can tell when
+    // it goes silent while it still draws the colors and progress lines meant for a terminal.
+    let mut pty_output = None;
+    if spinner {
+        let pty = openpty(terminal_size().as_ref(), None)?;
+        cmd.stdout(Stdio::from(pty.slave.try_clone()?))
+            .stderr(Stdio::from(pty.slave));
+        pty_output = Some(File::from(pty.master));
+    } else {
+        cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    }
     if let Some(dir) = working_dir {
         cmd.current_dir(dir);
     }
-    let mut command_process = cmd.spawn()?;
+    if timeout.is_some() {
+        forward_signals_to_timed_group();
+        cmd.process_group(0);
+    }
 
-    let status = command_process.wait()?;
+    trace(&format!("START {program} {shown_args}"));
+    let start = Instant::now();
+    let spawned = cmd.spawn();
+    // Closes our end of the child's terminal, so its output ends when the child exits.
+    drop(cmd);
+    let result = spawned.map_err(anyhow::Error::from).and_then(|mut child| {
+        if timeout.is_some() {
+            TIMED_GROUP.store(child.id() as i32, Ordering::SeqCst);
+        }
+        let status = if let Some(output) = pty_output {
+            wait_with_spinner(&mut child, output, timeout)
+        } else {
+            wait_with_timeout(&mut child, timeout)
+        };
+        TIMED_GROUP.store(0, Ordering::SeqCst);
+        status
+    });
+    let outcome = match &result {
+        Ok(status) => trace_outcome(&Ok(*status)),
+        Err(e) => format!("error={:?}", e.to_string()),
+    };
+    trace(&format!(
+        "END {outcome} duration={:.3}s {program} {shown_args}",
+        start.elapsed().as_secs_f64()
+    ));
+    let status = result?;
 
     if !status.success() {
-        return Err(anyhow::anyhow!(
-            "Command failed with exit code: {}",
-            status.code().unwrap_or(1)
-        ));
+        return Err(Error::CommandFailed(status.code().unwrap_or(1)).into());
     }
 
     Ok(())
 }
 
+/// Runs the external commands `FlatpakManager` needs, so tests can record them instead.
+/// Runners are shared by the threads building independent modules, so they must be `Sync`.
+pub trait CommandRunner: Sync {
+    /// Runs a command like `run_command_with_timeout`.
+    fn run(
+        &self,
+        command: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        working_dir: Option<&std::path::Path>,
+        timeout: Option<Duration>,
+    ) -> Result<()>;
+
+    /// Runs flatpak-builder like `flatpak_builder`.
+    fn flatpak_builder(
+        &self,
+        args: &[&str],
+        env: &[(String, String)],
+        working_dir: Option<&std::path::Path>,
+        timeout: Option<Duration>,
+    ) -> Result<()>;
+
+    /// Returns true if the command executes successfully, like `host_command_succeeds`.
+    fn succeeds(&self, command: &str, args: &[&str]) -> bool;
+
+    /// Returns the stdout of the command if it executes successfully, like `host_command_output`.
+    fn output(&self, command: &str, args: &[&str]) -> Option<String>;
+
+    /// Returns true if the given command is available on the host.
+    fn available(&self, command: &str) -> bool {
+        self.succeeds("which", &[command])
+    }
+}
+
+/// Runs commands for real, on the host when inside a Flatpak sandbox.
+pub struct HostRunner;
+
+impl CommandRunner for HostRunner {
+    fn run(
+        &self,
+        command: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        working_dir: Option<&std::path::Path>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let args = verbose_args(args, command == flatpak_bin() && flatpak_verbose());
+        run_command_with_timeout(command, &args, env, working_dir, timeout)
+    }
+
+    fn flatpak_builder(
+        &self,
+        args: &[&str],
+        env: &[(String, String)],
+        working_dir: Option<&std::path::Path>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        flatpak_builder(args, env, working_dir, timeout)
+    }
+
+    fn succeeds(&self, command: &str, args: &[&str]) -> bool {
+        host_command_succeeds(command, args)
+    }
+
+    fn output(&self, command: &str, args: &[&str]) -> Option<String> {
+        host_command_output(command, args)
+    }
+}
+
+// Applies the container workarounds to flatpak-builder arguments, whichever builder is used.
+fn builder_args<'a>(args: &[&'a str], inside_container: bool) -> Vec<&'a str> {
+    let mut builder_args = args.to_vec();
+
+    // Workaround for rofiles-fuse issues in containers.
+    if inside_container && !builder_args.contains(&"--disable-rofiles-fuse") {
+        builder_args.push("--disable-rofiles-fuse");
+    }
+
+    builder_args
+}
+
 // Runs flatpak-builder, preferring the native binary, then the Flatpak app.
-pub fn flatpak_builder(args: &[&str], working_dir: Option<&std::path::Path>) -> Result<()> {
+pub fn flatpak_builder(
+    args: &[&str],
+    env: &[(String, String)],
+    working_dir: Option<&std::path::Path>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let args = verbose_args(
+        &builder_args(args, is_inside_container()),
+        flatpak_verbose(),
+    );
     if command_succeeds("flatpak-builder", &["--version"]) {
-        run_command("flatpak-builder", args, working_dir)
-    } else if command_succeeds("flatpak", &["run", "org.flatpak.Builder", "--version"]) {
+        verbose_note("Using the flatpak-builder binary");
+        run_command_with_spinner("flatpak-builder", &args, env, working_dir, timeout)
+    } else if command_succeeds(flatpak_bin(), &["run", "org.flatpak.Builder", "--version"]) {
+        verbose_note(&format!(
+            "Using flatpak-builder from `{} run org.flatpak.Builder`",
+            flatpak_bin()
+        ));
         let mut new_args = vec!["run", "org.flatpak.Builder"];
-        new_args.extend_from_slice(args);
-        run_command("flatpak", &new_args, working_dir)
+        new_args.extend_from_slice(&args);
+        run_command_with_spinner(flatpak_bin(), &new_args, env, working_dir, timeout)
     } else {
         Err(anyhow::anyhow!(
             "Flatpak builder not found. Please install either `flatpak-builder` from your distro repositories or `org.flatpak.Builder` through `flatpak install`."
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_strategies() {
+        assert_eq!(spawn_strategy("flatpak", "flatpak"), "native");
+        assert_eq!(spawn_strategy("flatpak", "host-spawn"), "host-spawn");
+        assert_eq!(spawn_strategy("flatpak", "flatpak-spawn"), "flatpak-spawn");
+    }
+
+    #[test]
+    fn container_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        assert!(!detect_container(root, None));
+        assert!(!detect_container(root, Some("")));
+        assert!(!detect_container(root, Some("flatpak")));
+        assert!(detect_container(root, Some("podman")));
+        assert!(detect_container(root, Some("docker")));
+
+        std::fs::write(root.join(".dockerenv"), "").unwrap();
+        assert!(detect_container(root, None));
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("run")).unwrap();
+        std::fs::write(root.join("run/.containerenv"), "").unwrap();
+        assert!(detect_container(root, None));
+    }
+
+    #[test]
+    fn rofiles_fuse_disabled_only_in_containers() {
+        assert_eq!(builder_args(&["--ccache"], false), vec!["--ccache"]);
+        assert_eq!(
+            builder_args(&["--ccache"], true),
+            vec!["--ccache", "--disable-rofiles-fuse"]
+        );
+        assert_eq!(
+            builder_args(&["--disable-rofiles-fuse"], true),
+            vec!["--disable-rofiles-fuse"]
+        );
+    }
+
+    #[test]
+    fn verbose_flag_goes_first() {
+        assert_eq!(
+            verbose_args(&["build", "repo"], false),
+            vec!["build", "repo"]
+        );
+        assert_eq!(
+            verbose_args(&["build", "repo"], true),
+            vec!["-v", "build", "repo"]
+        );
+    }
+
+    #[test]
+    fn spinner_command_output_and_status() {
+        assert!(
+            spawn_command(
+                "sh",
+                &["-c", "echo out; echo err >&2"],
+                &[],
+                None,
+                None,
+                true
+            )
+            .is_ok()
+        );
+        // Its output goes to a terminal, so it keeps its colors and progress lines.
+        let on_terminal = spawn_command(
+            "sh",
+            &["-c", "test -t 1 && test -t 2"],
+            &[],
+            None,
+            None,
+            true,
+        );
+        assert!(on_terminal.is_ok());
+        let err = spawn_command("sh", &["-c", "exit 3"], &[], None, None, true).unwrap_err();
+        assert_eq!(err.to_string(), "Command failed with exit code: 3");
+    }
+
+    #[test]
+    fn timeout_kills_the_whole_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!("sleep 60 & echo $! > {}; wait", pid_file.display());
+        let timeout = Some(Duration::from_secs(1));
+        let err = spawn_command("sh", &["-c", &script], &[], None, timeout, false).unwrap_err();
+        assert_eq!(err.to_string(), "Command timed out after 1 seconds");
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat_file = format!("/proc/{}/stat", pid.trim());
+        // The orphaned sleep is either gone or a zombie nobody reaped yet.
+        let killed = || {
+            std::fs::read_to_string(&stat_file)
+                .map_or(true, |stat| stat.split_whitespace().nth(2) == Some("Z"))
+        };
+        let start = Instant::now();
+        while !killed() && start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(TIMEOUT_POLL_INTERVAL);
+        }
+        assert!(killed());
+    }
+
+    #[test]
+    fn flatpak_bin_precedence() {
+        let configured = Some("/opt/flatpak/bin/flatpak".to_string());
+        assert_eq!(resolve_flatpak_bin(None, None), "flatpak");
+        assert_eq!(
+            resolve_flatpak_bin(None, configured.clone()),
+            "/opt/flatpak/bin/flatpak"
+        );
+        assert_eq!(
+            resolve_flatpak_bin(Some("flatpak-wrapper".to_string()), configured.clone()),
+            "flatpak-wrapper"
+        );
+        assert_eq!(
+            resolve_flatpak_bin(Some(String::new()), configured),
+            "/opt/flatpak/bin/flatpak"
+        );
+    }
+
+    #[test]
+    fn secret_env_values_are_redacted() {
+        let patterns = ["*TOKEN*", "*SECRET*", "MY_KEY"];
+        assert_eq!(
+            redact_arg("--env=GITHUB_TOKEN=abc", &patterns),
+            "--env=GITHUB_TOKEN=***"
+        );
+        assert_eq!(
+            redact_arg("--env=client_secret=a=b", &patterns),
+            "--env=client_secret=***"
+        );
+        assert_eq!(
+            redact_arg("--env=MY_KEY=abc", &patterns),
+            "--env=MY_KEY=***"
+        );
+        assert_eq!(redact_arg("--env=LANG=C", &patterns), "--env=LANG=C");
+        assert_eq!(
+            redact_arg("--socket=wayland", &patterns),
+            "--socket=wayland"
+        );
+    }
+}
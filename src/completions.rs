@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::{Arg, Command, ValueEnum};
+
+/// Shells flatplay can generate completions for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Elvish,
+    Fish,
+    Nushell,
+    #[value(name = "powershell")]
+    PowerShell,
+    Zsh,
+}
+
+/// Writes the completion script for `shell` to `out`.
+pub fn generate(shell: Shell, cmd: &mut Command, bin_name: &str, out: &mut dyn std::io::Write) {
+    let clap_shell = match shell {
+        Shell::Bash => clap_complete::Shell::Bash,
+        Shell::Elvish => clap_complete::Shell::Elvish,
+        Shell::Fish => clap_complete::Shell::Fish,
+        Shell::PowerShell => clap_complete::Shell::PowerShell,
+        Shell::Zsh => clap_complete::Shell::Zsh,
+        Shell::Nushell => {
+            cmd.build();
+            out.write_all(nushell_completions(cmd, bin_name).as_bytes())
+                .expect("failed to write completion file");
+            return;
+        }
+    };
+    clap_complete::generate(clap_shell, cmd, bin_name, out);
+}
+
+/// Returns where `--install` writes the completions for `shell`, under the XDG data directory,
+/// or `None` for shells without a directory they load completions from on their own.
+pub fn install_path(shell: Shell, data_home: &Path, bin_name: &str) -> Option<PathBuf> {
+    match shell {
+        Shell::Bash => Some(data_home.join("bash-completion/completions").join(bin_name)),
+        Shell::Fish => Some(
+            data_home
+                .join("fish/vendor_completions.d")
+                .join(format!("{bin_name}.fish")),
+        ),
+        // Not in zsh's default fpath, so it has to be added by the user.
+        Shell::Zsh => Some(
+            data_home
+                .join("zsh/site-functions")
+                .join(format!("_{bin_name}")),
+        ),
+        Shell::Elvish | Shell::Nushell | Shell::PowerShell => None,
+    }
+}
+
+/// Returns the XDG data directory: `XDG_DATA_HOME` if set to an absolute path, else
+/// `~/.local/share`.
+fn data_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+}
+
+/// Writes the completion script for `shell` to its conventional location and returns the path.
+pub fn install(shell: Shell, cmd: &mut Command, bin_name: &str) -> Result<PathBuf> {
+    let data_home =
+        data_home().ok_or_else(|| anyhow::anyhow!("Neither XDG_DATA_HOME nor HOME is set"))?;
+    let Some(path) = install_path(shell, &data_home, bin_name) else {
+        return Err(anyhow::anyhow!(
+            "{:?} has no standard completions directory. Redirect the output of `{} completions` to where your shell loads it from.",
+            shell,
+            bin_name
+        ));
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut script = Vec::new();
+    generate(shell, cmd, bin_name, &mut script);
+    fs::write(&path, script).map_err(|e| anyhow::anyhow!("Failed to write {:?}: {}", path, e))?;
+    Ok(path)
+}
+
+fn help_comment(help: Option<String>) -> String {
+    match help {
+        Some(help) if !help.is_empty() => format!("  # {}", help.lines().next().unwrap_or("")),
+        _ => String::new(),
+    }
+}
+
+/// Returns the name of the nushell completer listing the possible values of `arg`, if any.
+fn value_completer(command_path: &str, arg: &Arg) -> Option<String> {
+    if arg.get_possible_values().is_empty() {
+        return None;
+    }
+    Some(format!("nu-complete {command_path} {}", arg.get_id()))
+}
+
+fn write_nushell_command(script: &mut String, cmd: &Command, command_path: &str) {
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        if let Some(completer) = value_completer(command_path, arg) {
+            let values: Vec<String> = arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| format!("\"{}\"", value.get_name()))
+                .collect();
+            let _ = writeln!(
+                script,
+                "  def \"{completer}\" [] {{\n    [ {} ]\n  }}\n",
+                values.join(" ")
+            );
+        }
+    }
+
+    if let Some(about) = cmd.get_about() {
+        let _ = writeln!(script, "  # {about}");
+    }
+    let _ = writeln!(script, "  export extern \"{command_path}\" [");
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        let help = help_comment(arg.get_help().map(|help| help.to_string()));
+        let type_suffix = match value_completer(command_path, arg) {
+            Some(completer) => format!(": string@\"{completer}\""),
+            None => ": string".to_string(),
+        };
+        if arg.is_positional() {
+            let multiple = arg
+                .get_num_args()
+                .is_some_and(|range| range.max_values() > 1);
+            let name = arg.get_id().as_str().replace('_', "-");
+            let declaration = if multiple {
+                format!("...{name}{type_suffix}")
+            } else if arg.is_required_set() {
+                format!("{name}{type_suffix}")
+            } else {
+                format!("{name}?{type_suffix}")
+            };
+            let _ = writeln!(script, "    {declaration}{help}");
+            continue;
+        }
+
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let short = arg
+            .get_short()
+            .map(|s| format!("(-{s})"))
+            .unwrap_or_default();
+        let value = if arg.get_action().takes_values() {
+            type_suffix
+        } else {
+            String::new()
+        };
+        let _ = writeln!(script, "    --{long}{short}{value}{help}");
+        for alias in arg.get_visible_aliases().unwrap_or_default() {
+            let _ = writeln!(script, "    --{alias}{value}{help}");
+        }
+    }
+    let _ = writeln!(script, "  ]\n");
+
+    for subcommand in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let path = format!("{command_path} {}", subcommand.get_name());
+        write_nushell_command(script, subcommand, &path);
+    }
+}
+
+/// Generates nushell `extern` definitions for `cmd` and its subcommands.
+/// `cmd` must be built so that global arguments are propagated to the subcommands.
+fn nushell_completions(cmd: &Command, bin_name: &str) -> String {
+    let mut script = "module completions {\n\n".to_string();
+    write_nushell_command(&mut script, cmd, bin_name);
+    script.push_str("}\n\nexport use completions *\n");
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+
+    #[derive(Parser)]
+    #[command(name = "example")]
+    struct Cli {
+        /// Target architecture
+        #[arg(long, global = true)]
+        arch: Option<String>,
+        #[command(subcommand)]
+        command: Commands,
+    }
+
+    #[derive(Subcommand)]
+    enum Commands {
+        /// Run the application
+        Run {
+            /// Don't grant GPU access
+            #[arg(long, action = ArgAction::SetTrue)]
+            no_device: bool,
+            #[arg(last = true)]
+            args: Vec<String>,
+        },
+        /// Generate completions
+        Completions {
+            #[arg(value_enum)]
+            shell: Shell,
+        },
+    }
+
+    #[test]
+    fn install_paths() {
+        let data_home = Path::new("/home/user/.local/share");
+        assert_eq!(
+            install_path(Shell::Bash, data_home, "example"),
+            Some(data_home.join("bash-completion/completions/example"))
+        );
+        assert_eq!(
+            install_path(Shell::Fish, data_home, "example"),
+            Some(data_home.join("fish/vendor_completions.d/example.fish"))
+        );
+        assert_eq!(
+            install_path(Shell::Zsh, data_home, "example"),
+            Some(data_home.join("zsh/site-functions/_example"))
+        );
+        assert_eq!(install_path(Shell::Nushell, data_home, "example"), None);
+    }
+
+    #[test]
+    fn nushell_externs() {
+        let mut cmd = Cli::command();
+        cmd.build();
+        let script = nushell_completions(&cmd, "example");
+
+        assert!(script.starts_with("module completions {\n"));
+        assert!(script.ends_with("export use completions *\n"));
+        assert!(script.contains("  export extern \"example run\" [\n"));
+        assert!(script.contains("    --arch: string  # Target architecture\n"));
+        assert!(script.contains("    --no-device  # Don't grant GPU access\n"));
+        assert!(script.contains("    ...args: string\n"));
+        assert!(script.contains("    shell: string@\"nu-complete example completions shell\"\n"));
+        assert!(
+            script.contains("[ \"bash\" \"elvish\" \"fish\" \"nushell\" \"powershell\" \"zsh\" ]")
+        );
+    }
+}
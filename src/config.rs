@@ -0,0 +1,233 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+const CONFIG_FILE_NAME: &str = ".flatplay.toml";
+
+/// Commands a bare `flatplay` can run, named like their subcommands.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DefaultCommand {
+    #[default]
+    BuildAndRun,
+    Build,
+    Run,
+    Rebuild,
+    UpdateDependencies,
+}
+
+/// Project-level settings read from `.flatplay.toml` at the repository root.
+/// CLI flags override these, and these override the built-in defaults.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Manifest to use when none has been selected yet.
+    pub manifest: Option<PathBuf>,
+    /// Number of parallel build jobs.
+    pub jobs: Option<u32>,
+    /// Number of dependency modules marked `x-flatplay-independent` built at the same time.
+    pub max_parallel_modules: Option<u32>,
+    /// Extra globs of paths to skip when looking for manifests, on top of vendored directories.
+    pub manifest_ignore: Vec<String>,
+    /// Extra environment variables to forward to the running application.
+    pub forward_env: Vec<String>,
+    /// Extra env var name patterns, like `*_TOKEN`, `run --env-passthrough-all` doesn't forward.
+    pub env_passthrough_deny: Vec<String>,
+    /// Extra env var name patterns, like `*_KEY`, whose values are masked in printed commands.
+    pub redact_env: Vec<String>,
+    /// Location of the build directory, relative to the repository root. The state file stays in
+    /// `.flatplay` either way.
+    pub build_dir: Option<PathBuf>,
+    /// Location of the repo directory the app is built into, relative to the repository root.
+    pub repo_dir: Option<PathBuf>,
+    /// Target architecture.
+    pub arch: Option<String>,
+    /// flatpak binary to run instead of `flatpak`. `FLATPLAY_FLATPAK_BIN` takes precedence.
+    pub flatpak_bin: Option<String>,
+    /// Flatpak installation of the runtimes: `user`, `system` or a custom installation name.
+    pub installation: Option<String>,
+    /// Whether flatpak-builder uses ccache for dependencies.
+    pub ccache: bool,
+    /// Shared ccache directory, relative to the repository root.
+    pub ccache_dir: Option<PathBuf>,
+    /// Whether flatpak-builder keeps the build directories of dependencies.
+    pub keep_build_dirs: bool,
+    /// Extra flags passed verbatim to flatpak-builder.
+    pub builder_args: Vec<String>,
+    /// Timeout in seconds for builder and build system commands.
+    pub command_timeout: Option<u64>,
+    /// Whether to send a desktop notification when a build finishes.
+    pub notify: bool,
+    /// Whether to install a missing runtime and SDK instead of failing.
+    pub install_deps: bool,
+    /// Command run by `flatplay` without a subcommand.
+    pub default_command: DefaultCommand,
+    /// Shell commands run in the project root after a successful build.
+    pub post_build: Vec<String>,
+    /// Shell commands run in the project root after a successful export.
+    pub post_export: Vec<String>,
+    /// Remove the intermediate build directories after a successful build. Only set from the CLI.
+    #[serde(skip)]
+    pub prune: bool,
+    /// Build the application with the debug build type. Only set from the CLI.
+    #[serde(skip)]
+    pub debug: bool,
+    /// Log every command to `trace.log` in the build directory. Only set from the CLI.
+    #[serde(skip)]
+    pub trace: bool,
+    /// Note how commands are run, like the flatpak-builder picked. Only set from the CLI.
+    #[serde(skip)]
+    pub verbose: bool,
+    /// Pass `-v` to flatpak and flatpak-builder. Only set from the CLI.
+    #[serde(skip)]
+    pub verbose_flatpak: bool,
+    /// Build the application even if the dependencies failed to build. Only set from the CLI.
+    #[serde(skip)]
+    pub keep_going: bool,
+    /// Version stamped into the app build and the export. Only set from the CLI.
+    #[serde(skip)]
+    pub version: Option<String>,
+    /// Git commit the export is annotated with. Only set from the CLI.
+    #[serde(skip)]
+    pub git_commit: Option<String>,
+    /// Application branch to export to. Only set from the CLI.
+    #[serde(skip)]
+    pub branch: Option<String>,
+    /// Resolve prompts to their default answer instead of asking. Only set from the CLI.
+    #[serde(skip)]
+    pub non_interactive: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            manifest: None,
+            jobs: None,
+            max_parallel_modules: None,
+            manifest_ignore: Vec::new(),
+            forward_env: Vec::new(),
+            env_passthrough_deny: Vec::new(),
+            redact_env: Vec::new(),
+            build_dir: None,
+            repo_dir: None,
+            arch: None,
+            flatpak_bin: None,
+            installation: None,
+            ccache: true,
+            ccache_dir: None,
+            keep_build_dirs: true,
+            builder_args: Vec::new(),
+            command_timeout: None,
+            notify: false,
+            install_deps: false,
+            default_command: DefaultCommand::default(),
+            post_build: Vec::new(),
+            post_export: Vec::new(),
+            non_interactive: false,
+            prune: false,
+            debug: false,
+            trace: false,
+            verbose: false,
+            verbose_flatpak: false,
+            keep_going: false,
+            branch: None,
+            version: None,
+            git_commit: None,
+        }
+    }
+}
+
+impl Config {
+    fn config_file_path(base_dir: &Path) -> PathBuf {
+        base_dir.join(CONFIG_FILE_NAME)
+    }
+
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let config_file = Self::config_file_path(base_dir);
+        if !config_file.exists() {
+            return Ok(Config::default());
+        }
+        let content = fs::read_to_string(&config_file)?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", CONFIG_FILE_NAME, e))?;
+        if let Some(build_dir) = &config.build_dir {
+            validate_build_dir(build_dir)?;
+        }
+        Ok(config)
+    }
+}
+
+/// Checks that the build dir is a subdirectory of the project. `clean` removes it as a whole,
+/// so it must never be the project itself, one of its parents or a dir outside of it.
+fn validate_build_dir(build_dir: &Path) -> Result<()> {
+    let is_subdir = build_dir.components().next().is_some()
+        && build_dir
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !is_subdir {
+        return Err(anyhow::anyhow!(
+            "Invalid build_dir {:?} in {}: it must be a subdirectory of the project, like \".flatplay\"",
+            build_dir,
+            CONFIG_FILE_NAME
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_dir_must_be_inside_the_project() {
+        let dir = tempfile::tempdir().unwrap();
+        for build_dir in ["", ".", "./", "..", "../build", "/home/me", "build/../.."] {
+            fs::write(
+                dir.path().join(CONFIG_FILE_NAME),
+                format!("build_dir = {build_dir:?}\n"),
+            )
+            .unwrap();
+            let err = Config::load(dir.path()).unwrap_err().to_string();
+            assert!(err.contains("Invalid build_dir"), "{build_dir}: {err}");
+        }
+
+        fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "build_dir = \"build/flatplay\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Config::load(dir.path()).unwrap().build_dir,
+            Some(PathBuf::from("build/flatplay"))
+        );
+    }
+
+    #[test]
+    fn default_command_is_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            Config::load(dir.path()).unwrap().default_command,
+            DefaultCommand::BuildAndRun
+        );
+
+        fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "default_command = \"build\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Config::load(dir.path()).unwrap().default_command,
+            DefaultCommand::Build
+        );
+
+        fs::write(
+            dir.path().join(CONFIG_FILE_NAME),
+            "default_command = \"clean\"\n",
+        )
+        .unwrap();
+        let err = Config::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("unknown variant `clean`"), "{err}");
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// Failures that get a dedicated exit code, so scripts can tell them apart.
+/// Any other error exits with 1.
+#[derive(Debug)]
+pub enum Error {
+    NoManifest,
+    MissingRuntimes(String),
+    BuildFailed(anyhow::Error),
+    AlreadyRunning(u32),
+    /// A command exited with a nonzero code.
+    CommandFailed(i32),
+    /// The app exited with a nonzero code under `run --headless`, which exits with it too.
+    AppExited(i32),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NoManifest => 2,
+            Error::MissingRuntimes(_) => 3,
+            Error::BuildFailed(_) => 4,
+            Error::AlreadyRunning(_) => 5,
+            Error::CommandFailed(_) => 1,
+            Error::AppExited(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoManifest => write!(f, "No manifest found."),
+            Error::MissingRuntimes(message) => write!(f, "{message}"),
+            Error::BuildFailed(err) => write!(f, "{err}"),
+            Error::AlreadyRunning(pid) => write!(
+                f,
+                "Another instance of flatplay is already running (PID: {pid})."
+            ),
+            Error::CommandFailed(code) => write!(f, "Command failed with exit code: {code}"),
+            Error::AppExited(code) => write!(f, "The application exited with code {code}."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns the exit code for an error, 1 unless it is one of ours.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<Error>().map_or(1, Error::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(&anyhow::anyhow!("Something else")), 1);
+        assert_eq!(exit_code(&Error::NoManifest.into()), 2);
+        let build_failed = Error::BuildFailed(anyhow::anyhow!("Command failed with exit code: 2"));
+        assert_eq!(build_failed.to_string(), "Command failed with exit code: 2");
+        assert_eq!(exit_code(&build_failed.into()), 4);
+        assert_eq!(exit_code(&Error::CommandFailed(7).into()), 1);
+        assert_eq!(exit_code(&Error::AppExited(7).into()), 7);
+    }
+}
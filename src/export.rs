@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use walkdir::WalkDir;
+
+/// Renames `share/applications/<desktop_file>` to `<app_id>.desktop`, pointing its `Icon` key
+/// at the app id when the icon is renamed as well.
+pub fn rename_desktop_file(
+    share_dir: &Path,
+    desktop_file: &str,
+    app_id: &str,
+    rename_icon: Option<&str>,
+) -> Result<()> {
+    let applications_dir = share_dir.join("applications");
+    let source = applications_dir.join(desktop_file);
+    if !source.is_file() {
+        return Err(anyhow::anyhow!(
+            "Desktop file to rename not found at {:?}",
+            source
+        ));
+    }
+
+    let mut content = fs::read_to_string(&source)?;
+    if let Some(icon) = rename_icon {
+        content = content
+            .lines()
+            .map(|line| {
+                if line.strip_prefix("Icon=") == Some(icon) {
+                    format!("Icon={app_id}")
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        content.push('\n');
+    }
+
+    fs::write(applications_dir.join(format!("{app_id}.desktop")), content)?;
+    fs::remove_file(source)?;
+    Ok(())
+}
+
+/// Renames every icon under `share/icons` named `<icon>` or `<icon>-symbolic` to use the app id.
+/// Returns the number of renamed icons.
+pub fn rename_icon(share_dir: &Path, icon: &str, app_id: &str) -> Result<usize> {
+    let icons_dir = share_dir.join("icons");
+    if !icons_dir.is_dir() {
+        return Ok(0);
+    }
+
+    let symbolic_icon = format!("{icon}-symbolic");
+    let mut renamed = 0;
+    for entry in WalkDir::new(&icons_dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let (Some(stem), Some(extension)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|s| s.to_str()),
+        ) else {
+            continue;
+        };
+
+        let new_stem = if stem == icon {
+            app_id.to_string()
+        } else if stem == symbolic_icon {
+            format!("{app_id}-symbolic")
+        } else {
+            continue;
+        };
+        fs::rename(path, path.with_file_name(format!("{new_stem}.{extension}")))?;
+        renamed += 1;
+    }
+
+    Ok(renamed)
+}
+
+/// Returns a `.flatpakref` file installing `app_id` from the repo at `url`, so it can be
+/// installed with `flatpak install --from`. Like `build-export`, the branch defaults to `master`.
+pub fn flatpakref(app_id: &str, branch: Option<&str>, url: &str, is_runtime: bool) -> String {
+    format!(
+        "[Flatpak Ref]\nName={}\nBranch={}\nUrl={}\nIsRuntime={}\n",
+        app_id,
+        branch.unwrap_or("master"),
+        url,
+        is_runtime
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_desktop_file_and_icons() {
+        let dir = tempfile::tempdir().unwrap();
+        let share_dir = dir.path();
+        let applications_dir = share_dir.join("applications");
+        let icons_dir = share_dir.join("icons/hicolor/scalable/apps");
+        let symbolic_dir = share_dir.join("icons/hicolor/symbolic/apps");
+        fs::create_dir_all(&applications_dir).unwrap();
+        fs::create_dir_all(&icons_dir).unwrap();
+        fs::create_dir_all(&symbolic_dir).unwrap();
+        fs::write(
+            applications_dir.join("example.desktop"),
+            "[Desktop Entry]\nName=Example\nIcon=example\n",
+        )
+        .unwrap();
+        fs::write(icons_dir.join("example.svg"), "").unwrap();
+        fs::write(symbolic_dir.join("example-symbolic.svg"), "").unwrap();
+
+        rename_desktop_file(
+            share_dir,
+            "example.desktop",
+            "com.example.Devel",
+            Some("example"),
+        )
+        .unwrap();
+        let renamed = rename_icon(share_dir, "example", "com.example.Devel").unwrap();
+
+        assert_eq!(renamed, 2);
+        assert!(!applications_dir.join("example.desktop").exists());
+        let desktop =
+            fs::read_to_string(applications_dir.join("com.example.Devel.desktop")).unwrap();
+        assert!(desktop.contains("Icon=com.example.Devel\n"));
+        assert!(icons_dir.join("com.example.Devel.svg").is_file());
+        assert!(
+            symbolic_dir
+                .join("com.example.Devel-symbolic.svg")
+                .is_file()
+        );
+    }
+}
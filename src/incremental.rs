@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use walkdir::WalkDir;
+
+use crate::manifest::{Module, expand_module_reference, load_module_reference};
+
+/// Directories never hashed as module inputs, as they hold build outputs or VCS data.
+const SKIPPED_DIRS: [&str; 3] = [".git", ".flatplay", ".flatpak-builder"];
+
+/// 64-bit FNV-1a. The hashes are persisted in the state file, so they must not change between
+/// builds of flatplay the way `DefaultHasher` may.
+struct Fnv64(u64);
+
+impl Fnv64 {
+    fn new() -> Self {
+        Fnv64(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Writes a length-prefixed field, so consecutive fields can't run into each other.
+    fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Hashes a local file, or every file under a local directory, including their relative paths.
+fn hash_local_path(hasher: &mut Fnv64, path: &Path, exclude: &[PathBuf]) -> Result<()> {
+    if path.is_file() {
+        hasher.write_field(&fs::read(path)?);
+        return Ok(());
+    }
+    if !path.is_dir() {
+        // Missing inputs hash to a marker, so creating them later counts as a change.
+        hasher.write_field(b"<missing>");
+        return Ok(());
+    }
+
+    let walker = WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            let is_skipped_name = e
+                .file_name()
+                .to_str()
+                .is_some_and(|name| SKIPPED_DIRS.contains(&name));
+            !is_skipped_name && !exclude.iter().any(|prefix| e.path().starts_with(prefix))
+        });
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative_path = entry.path().strip_prefix(path)?;
+        hasher.write_field(relative_path.to_string_lossy().as_bytes());
+        hasher.write_field(&fs::read(entry.path())?);
+    }
+    Ok(())
+}
+
+/// Returns a hash of a module's inputs: its definition plus the contents of its local sources
+/// (`path`/`paths` of `dir`, `file`, `patch` and similar sources), resolved from `manifest_dir`.
+/// Remote sources only contribute their definition, so a moving git branch isn't detected.
+pub fn module_input_hash(
+    module: &Module,
+    manifest_dir: &Path,
+    exclude: &[PathBuf],
+) -> Result<String> {
+    let mut hasher = Fnv64::new();
+    hasher.write_field(serde_json::to_string(module)?.as_bytes());
+
+    match module {
+        Module::Object { sources, .. } => {
+            for source in sources {
+                let paths = source["path"].as_str().into_iter().chain(
+                    source["paths"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|p| p.as_str()),
+                );
+                for path in paths {
+                    hash_local_path(&mut hasher, &manifest_dir.join(path), exclude)?;
+                }
+            }
+        }
+        Module::Reference(reference) => {
+            for file in expand_module_reference(reference, manifest_dir)? {
+                hash_local_path(&mut hasher, &manifest_dir.join(file), exclude)?;
+            }
+            // The local sources of the referenced modules are inputs too.
+            for module in load_module_reference(reference, manifest_dir)? {
+                hasher.write_field(module_input_hash(&module, manifest_dir, exclude)?.as_bytes());
+            }
+        }
+    }
+
+    Ok(format!("{:016x}", hasher.0))
+}
+
+/// Returns a hash of the module definitions alone, sources included, without reading any local
+/// files. It changes when what flatpak-builder downloads changes, like a source URL or checksum.
+pub fn modules_definition_hash(modules: &[Module]) -> Result<String> {
+    let mut hasher = Fnv64::new();
+    for module in modules {
+        hasher.write_field(serde_json::to_string(module)?.as_bytes());
+    }
+    Ok(format!("{:016x}", hasher.0))
+}
+
+/// Returns the first module whose inputs hash differs from the recorded one, or that has no
+/// recorded hash.
+pub fn first_changed_module<'a>(
+    current: &'a [(String, String)],
+    recorded: &BTreeMap<String, String>,
+) -> Option<&'a str> {
+    current
+        .iter()
+        .find(|(name, hash)| recorded.get(name) != Some(hash))
+        .map(|(name, _)| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir_module(name: &str, path: &str) -> Module {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "sources": [{ "type": "dir", "path": path }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn local_source_changes_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("libfoo/.git")).unwrap();
+        fs::write(dir.path().join("libfoo/foo.c"), "int foo;").unwrap();
+        fs::write(dir.path().join("libbar.c"), "int bar;").unwrap();
+        let modules = [
+            dir_module("libfoo", "libfoo"),
+            dir_module("libbar", "libbar.c"),
+        ];
+
+        let hashes = |modules: &[Module]| -> Vec<(String, String)> {
+            modules
+                .iter()
+                .map(|m| {
+                    let hash = module_input_hash(m, dir.path(), &[]).unwrap();
+                    (m.name().to_string(), hash)
+                })
+                .collect()
+        };
+        let recorded: BTreeMap<String, String> = hashes(&modules).into_iter().collect();
+        assert_eq!(first_changed_module(&hashes(&modules), &recorded), None);
+
+        // VCS data is not an input.
+        fs::write(dir.path().join("libfoo/.git/index"), "changed").unwrap();
+        assert_eq!(first_changed_module(&hashes(&modules), &recorded), None);
+
+        fs::write(dir.path().join("libbar.c"), "int bar = 1;").unwrap();
+        assert_eq!(
+            first_changed_module(&hashes(&modules), &recorded),
+            Some("libbar")
+        );
+
+        fs::write(dir.path().join("libfoo/foo.h"), "extern int foo;").unwrap();
+        assert_eq!(
+            first_changed_module(&hashes(&modules), &recorded),
+            Some("libfoo")
+        );
+    }
+
+    #[test]
+    fn source_definition_changes_are_detected() {
+        let archive_module = |url: &str| -> Module {
+            serde_json::from_value(serde_json::json!({
+                "name": "libfoo",
+                "sources": [{ "type": "archive", "url": url, "sha256": "abc" }]
+            }))
+            .unwrap()
+        };
+        let recorded =
+            modules_definition_hash(&[archive_module("https://example.com/foo-1.0.tar.xz")])
+                .unwrap();
+        assert_eq!(
+            modules_definition_hash(&[archive_module("https://example.com/foo-1.0.tar.xz")])
+                .unwrap(),
+            recorded
+        );
+        assert_ne!(
+            modules_definition_hash(&[archive_module("https://example.com/foo-1.1.tar.xz")])
+                .unwrap(),
+            recorded
+        );
+    }
+}
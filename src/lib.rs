@@ -1,106 +1,303 @@
 mod build_dirs;
+pub mod check;
+mod cleanup;
 mod command;
+pub mod completions;
+pub mod config;
+pub mod error;
+mod export;
+mod incremental;
 mod manifest;
+pub mod options;
 pub mod process;
+pub mod runtimes;
 pub mod state;
+pub mod status;
 mod utils;
 
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use colored::*;
-use command::{flatpak_builder, run_command};
-use dialoguer::{theme::ColorfulTheme, Select};
+use command::{
+    CommandRunner, HostRunner, flatpak_bin, set_flatpak_bin, set_flatpak_verbose, set_redacted_env,
+    set_trace_file, set_verbose,
+};
+use dialoguer::{Select, theme::ColorfulTheme};
 
 use crate::build_dirs::BuildDirs;
-use crate::manifest::{find_manifests_in_path, Manifest, Module};
+use crate::cleanup::apply_cleanup;
+use crate::config::Config;
+use crate::error::Error;
+use crate::export::{flatpakref, rename_desktop_file, rename_icon};
+use crate::incremental::{first_changed_module, module_input_hash, modules_definition_hash};
+use crate::manifest::{
+    BuildOptions, BuildSystem, Manifest, Module, ModuleFilter, builder_manifest,
+    filtered_builder_manifest, find_manifests, is_manifest_url, remote_manifest_extension,
+};
+use crate::options::{DisplayBackend, RunOptions, strace_args};
 use crate::process::kill_process_group;
-use crate::state::State;
-use crate::utils::{get_a11y_bus_args, get_host_env};
+use crate::runtimes::missing_runtimes;
+use crate::state::{
+    BUILD_APPLICATION_STEP, BUILD_DEPENDENCIES_STEP, State, UPDATE_DEPENDENCIES_STEP,
+};
+use crate::utils::{
+    DATA_SUBDIRS, data_dir_args, dedup_args, dir_size, format_duration, format_size,
+    get_a11y_bus_args, get_device_args, get_display_backend_args, get_host_env, installation_arg,
+    list_executables, parse_env_file, passthrough_env, shell_quote, shell_split,
+};
 
 pub struct FlatpakManager<'a> {
     state: &'a mut State,
     manifest: Option<Manifest>,
     build_dirs: BuildDirs,
+    config: Config,
+    runner: Box<dyn CommandRunner>,
 }
 
 impl<'a> FlatpakManager<'a> {
-    fn find_manifests(&self) -> Result<Vec<PathBuf>> {
+    fn find_manifests(&self) -> Result<Vec<(PathBuf, Manifest)>> {
         let current_dir = std::env::current_dir()?;
-        let current_dir_canon = current_dir.canonicalize()?;
-        let base_dir_canon = self.state.base_dir.canonicalize()?;
-
-        let mut manifests = find_manifests_in_path(&current_dir, None)?;
-        if current_dir_canon != base_dir_canon {
-            manifests.extend(find_manifests_in_path(
-                &self.state.base_dir,
-                Some(&current_dir),
-            )?);
-        }
-        manifests.dedup();
-        Ok(manifests)
+        find_manifests(
+            &current_dir,
+            &self.state.base_dir,
+            &self.config.manifest_ignore,
+        )
     }
 
     fn auto_select_manifest(&mut self) -> Result<bool> {
         let manifests = self.find_manifests()?;
-        if let Some(manifest_path) = manifests.first() {
-            self.state.active_manifest = Some(manifest_path.clone());
-            self.state.save()?;
+        if let Some((manifest_path, manifest)) = manifests.into_iter().next() {
             println!("{} {:?}", "Auto-selected manifest:".green(), manifest_path);
-            self.manifest = Some(Manifest::from_file(manifest_path)?);
+            self.state.active_manifest = Some(manifest_path);
+            self.state.save()?;
+            self.manifest = Some(manifest);
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    pub fn new(state: &'a mut State) -> Result<Self> {
+    /// Selects the manifest configured in `.flatplay.toml`, if any.
+    fn select_configured_manifest(&mut self) -> Result<bool> {
+        let Some(path) = &self.config.manifest else {
+            return Ok(false);
+        };
+        let manifest_path = self.state.base_dir.join(path);
+        let manifest = Manifest::from_file(&manifest_path)?;
+        self.state.active_manifest = Some(manifest_path.clone());
+        self.state.save()?;
+        println!(
+            "{} {:?}",
+            "Selected manifest from config:".green(),
+            manifest_path
+        );
+        self.manifest = Some(manifest);
+        Ok(true)
+    }
+
+    pub fn new(state: &'a mut State, config: Config) -> Result<Self> {
+        Self::with_runner(state, config, Box::new(HostRunner))
+    }
+
+    /// Creates a manager running its commands through `runner`.
+    pub(crate) fn with_runner(
+        state: &'a mut State,
+        config: Config,
+        runner: Box<dyn CommandRunner>,
+    ) -> Result<Self> {
+        set_redacted_env(config.redact_env.clone());
+        set_flatpak_bin(config.flatpak_bin.clone());
+        // The manifest may have been removed since, e.g. a downloaded one by `clean`.
+        if let Some(path) = &state.active_manifest
+            && !path.exists()
+        {
+            eprintln!(
+                "{} The active manifest {:?} no longer exists.",
+                "⚠".yellow(),
+                path
+            );
+            state.active_manifest = None;
+        }
         let manifest = if let Some(path) = &state.active_manifest {
             Some(Manifest::from_file(path)?)
         } else {
             None
         };
-        let build_dirs = BuildDirs::new(state.base_dir.clone());
+        let build_dirs = BuildDirs::new(
+            state.base_dir.clone(),
+            config.build_dir.clone(),
+            config.repo_dir.clone(),
+            config.arch.clone(),
+        );
+        // Each arch is built in its own dirs, so progress made for another one doesn't count.
+        if state.arch != config.arch {
+            state.reset();
+            state.arch = config.arch.clone();
+            state.save()?;
+        }
+        if config.trace {
+            set_trace_file(build_dirs.trace_file());
+        }
+        set_flatpak_verbose(config.verbose_flatpak);
+        set_verbose(config.verbose);
         let mut manager = Self {
             state,
             manifest,
             build_dirs,
+            config,
+            runner,
         };
-        if manager.manifest.is_none() && !manager.auto_select_manifest()? {
-            return Err(anyhow::anyhow!("No manifest found."));
+        if manager.manifest.is_none()
+            && !manager.select_configured_manifest()?
+            && !manager.auto_select_manifest()?
+        {
+            return Err(Error::NoManifest.into());
+        }
+        if manager.config.repo_dir.is_some() {
+            manager.check_repo_dir_writable()?;
         }
         manager.init()?;
         Ok(manager)
     }
 
+    /// Returns the `FLATPLAY_MANIFEST` and `FLATPLAY_APP_ID` env vars given to every command
+    /// flatplay spawns, so hooks know what is being built. They follow `select-manifest`.
+    fn context_env(&self) -> Vec<(String, String)> {
+        let (Some(manifest_path), Some(manifest)) = (&self.state.active_manifest, &self.manifest)
+        else {
+            return Vec::new();
+        };
+        let manifest_path = std::path::absolute(manifest_path).unwrap_or(manifest_path.clone());
+        vec![
+            (
+                "FLATPLAY_MANIFEST".to_string(),
+                manifest_path.to_string_lossy().into_owned(),
+            ),
+            ("FLATPLAY_APP_ID".to_string(), manifest.id.clone()),
+        ]
+    }
+
+    /// Makes sure an overridden repo directory exists and can be written to.
+    fn check_repo_dir_writable(&self) -> Result<()> {
+        let repo_dir = self.build_dirs.repo_dir();
+        fs::create_dir_all(&repo_dir).map_err(|e| {
+            anyhow::anyhow!("Failed to create repo directory {:?}: {}", repo_dir, e)
+        })?;
+        let probe = repo_dir.join(".flatplay-write-check");
+        fs::write(&probe, "")
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| anyhow::anyhow!("Repo directory {:?} is not writable: {}", repo_dir, e))
+    }
+
     fn is_build_initialized(&self) -> Result<bool> {
-        let metadata_file = self.build_dirs.metadata_file();
-        let files_dir = self.build_dirs.files_dir();
-        let var_dir = self.build_dirs.var_dir();
+        Ok(self.build_dirs.is_repo_initialized())
+    }
+
+    /// Returns the `--arch` argument for the target arch, if one was requested.
+    fn arch_arg(&self) -> Option<String> {
+        self.build_dirs
+            .arch
+            .as_ref()
+            .map(|arch| format!("--arch={arch}"))
+    }
+
+    /// Returns the application branch: `--branch` if given, else the manifest `default-branch`.
+    /// When neither is set, flatpak picks its own default.
+    fn branch(&self) -> Option<&str> {
+        let manifest_branch = self
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest.default_branch.as_deref());
+        self.config.branch.as_deref().or(manifest_branch)
+    }
+
+    /// Returns the `-j` arguments for ninja/make, if a job count was configured.
+    fn jobs_args(&self) -> Vec<String> {
+        match self.config.jobs {
+            Some(jobs) => vec!["-j".to_string(), jobs.to_string()],
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the flag selecting the configured flatpak installation, if one was configured.
+    fn installation_arg(&self) -> Option<String> {
+        self.config.installation.as_deref().map(installation_arg)
+    }
 
-        // Check if all required directories and files exist
-        // From gnome-builder: https://gitlab.gnome.org/GNOME/gnome-builder/-/blob/8579055f5047a0af5462e8a587b0742014d71d64/src/plugins/flatpak/gbp-flatpak-pipeline-addin.c#L220
-        Ok(metadata_file.is_file() && files_dir.is_dir() && var_dir.is_dir())
+    /// Makes sure the runtime, SDK and SDK extensions are installed, installing them if
+    /// `--install-deps` is set. The extensions of a missing SDK are only known once it is
+    /// installed, so they are installed in a second round.
+    fn ensure_runtimes_installed(&self) -> Result<()> {
+        let manifest = self.manifest.as_ref().unwrap();
+        // Without a configured installation, runtimes from any of them will do.
+        let installation_arg = self.installation_arg();
+        for _ in 0..2 {
+            let missing = missing_runtimes(manifest, installation_arg.as_deref(), &*self.runner);
+            if missing.is_empty() {
+                return Ok(());
+            }
+
+            let mut args = vec![
+                "install",
+                installation_arg.as_deref().unwrap_or("--user"),
+                "-y",
+            ];
+            args.extend(missing.iter().map(|s| s.as_str()));
+            if !self.config.install_deps {
+                return Err(Error::MissingRuntimes(format!(
+                    "Missing runtimes: {}. Install them with `{} {}` or pass `--install-deps`.",
+                    missing.join(", "),
+                    flatpak_bin(),
+                    args.join(" ")
+                ))
+                .into());
+            }
+            println!("{}", "Installing missing runtimes...".bold());
+            self.run_command(flatpak_bin(), &args)?;
+        }
+        Ok(())
     }
 
     fn init_build(&self) -> Result<()> {
+        self.ensure_runtimes_installed()?;
+
         let manifest = self.manifest.as_ref().unwrap();
         let repo_dir = self.build_dirs.repo_dir();
 
         println!("{}", "Initializing build environment...".bold());
-        run_command(
-            "flatpak",
-            &[
-                "build-init",
-                repo_dir.to_str().unwrap(),
-                &manifest.id,
-                &manifest.sdk,
-                &manifest.runtime,
-                &manifest.runtime_version,
-            ],
-            Some(self.state.base_dir.as_path()),
-        )
+        // The trailing branch of build-init is the SDK and runtime one. The app branch is only
+        // chosen on export, from `branch()`.
+        let mut args: Vec<String> = vec!["build-init".to_string()];
+        args.extend(self.arch_arg());
+        args.extend(manifest.build_init_args());
+        args.extend([
+            repo_dir.to_str().unwrap().to_string(),
+            manifest.id.clone(),
+            manifest.sdk.clone(),
+            manifest.runtime.clone(),
+            manifest.runtime_version.clone(),
+        ]);
+
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+        self.run_command(flatpak_bin(), &args_str)?;
+
+        // build-init can't declare extension points, and build-finish only runs on export.
+        let extension_metadata = manifest.extension_metadata();
+        let metadata_file = self.build_dirs.metadata_file();
+        if !extension_metadata.is_empty() && metadata_file.is_file() {
+            let mut metadata = fs::read_to_string(&metadata_file)?;
+            metadata.push_str(&extension_metadata);
+            fs::write(&metadata_file, metadata)?;
+        }
+        Ok(())
     }
 
     pub fn init(&mut self) -> Result<()> {
@@ -108,175 +305,478 @@ impl<'a> FlatpakManager<'a> {
             return Ok(());
         }
 
+        // `build-init` refuses to run over the leftovers of an interrupted one.
+        if self.build_dirs.is_repo_partial() {
+            self.remove_partial_repo()?;
+            // Whatever was built into it is gone too.
+            self.state.reset();
+            self.state.save()?;
+        }
+
         self.init_build()?;
         Ok(())
     }
 
-    fn build_application(&self) -> Result<()> {
-        let manifest = self.manifest.as_ref().unwrap();
+    /// Removes the leftovers of an interrupted `build-init`. A repo dir outside the build dir
+    /// may be any directory, so only the entries `build-init` creates are removed from it, and
+    /// only when it holds nothing else.
+    fn remove_partial_repo(&self) -> Result<()> {
         let repo_dir = self.build_dirs.repo_dir();
-        let repo_dir_str = repo_dir.to_str().unwrap();
-
-        if let Some(module) = manifest.modules.last() {
-            match module {
-                Module::Object {
-                    buildsystem,
-                    config_opts,
-                    build_commands,
-                    post_install,
-                    ..
-                } => {
-                    match buildsystem.as_deref() {
-                        Some("meson") => self.run_meson(repo_dir_str, config_opts.as_ref())?,
-                        Some("cmake") | Some("cmake-ninja") => {
-                            self.run_cmake(repo_dir_str, config_opts.as_ref())?
-                        }
-                        Some("simple") => self.run_simple(repo_dir_str, build_commands.as_ref())?,
-                        _ => self.run_autotools(repo_dir_str, config_opts.as_ref())?,
-                    }
-                    if let Some(post_install) = post_install {
-                        for command in post_install {
-                            let args: Vec<&str> = command.split_whitespace().collect();
-                            run_command(args[0], &args[1..], Some(self.state.base_dir.as_path()))?;
-                        }
-                    }
-                }
-                Module::Reference(_) => {
-                    // Skip string references for build_application
+        if !self.build_dirs.is_repo_dir_external() {
+            eprintln!(
+                "{} Removing partially initialized repo {:?}.",
+                "⚠".yellow(),
+                repo_dir
+            );
+            fs::remove_dir_all(&repo_dir)?;
+            return Ok(());
+        }
+
+        let entries = self.build_dirs.repo_init_entries();
+        let has_other_files = fs::read_dir(&repo_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .iter()
+            .any(|path| !entries.contains(path));
+        if has_other_files {
+            return Err(anyhow::anyhow!(
+                "The repo directory {:?} looks partially initialized but holds other files too. Remove its metadata, files, usr and var entries or pick another `--repo-dir`.",
+                repo_dir
+            ));
+        }
+        eprintln!(
+            "{} Removing partially initialized repo contents in {:?}.",
+            "⚠".yellow(),
+            repo_dir
+        );
+        for entry in entries {
+            if entry.is_dir() {
+                fs::remove_dir_all(&entry)?;
+            } else if entry.exists() {
+                fs::remove_file(&entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a command in the base directory.
+    fn run_command(&self, command: &str, args: &[&str]) -> Result<()> {
+        self.runner.run(
+            command,
+            args,
+            &self.context_env(),
+            Some(self.state.base_dir.as_path()),
+            None,
+        )
+    }
+
+    /// Runs a build step in the app's source directory, bounded by the configured command timeout.
+    fn run_build_command(&self, command: &str, args: &[&str]) -> Result<()> {
+        self.runner.run(
+            command,
+            args,
+            &self.context_env(),
+            Some(self.app_source_dir()?.as_path()),
+            self.command_timeout(),
+        )
+    }
+
+    fn command_timeout(&self) -> Option<Duration> {
+        self.config.command_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns the architecture being built for: `--arch` if given, else the host's.
+    fn target_arch(&self) -> &str {
+        self.build_dirs
+            .arch
+            .as_deref()
+            .unwrap_or(std::env::consts::ARCH)
+    }
+
+    /// Returns the shared ccache directory, if ccache is enabled and one is configured.
+    fn ccache_dir(&self) -> Option<PathBuf> {
+        let ccache_dir = self.config.ccache_dir.as_ref()?;
+        self.config
+            .ccache
+            .then(|| self.state.base_dir.join(ccache_dir))
+    }
+
+    /// Creates the shared ccache directory and points flatpak-builder's ccache at it.
+    /// flatpak-builder always uses `<state-dir>/ccache` and overrides `CCACHE_DIR` in the
+    /// sandbox, so a symlink is the only way to redirect it.
+    fn prepare_ccache_dir(&self) -> Result<()> {
+        let Some(ccache_dir) = self.ccache_dir() else {
+            return Ok(());
+        };
+        fs::create_dir_all(&ccache_dir).map_err(|e| {
+            anyhow::anyhow!("Failed to create ccache directory {:?}: {}", ccache_dir, e)
+        })?;
+        let ccache_dir = ccache_dir.canonicalize()?;
+
+        let state_dir = self.build_dirs.flatpak_builder_dir();
+        fs::create_dir_all(&state_dir)?;
+        let link = state_dir.join("ccache");
+        match fs::symlink_metadata(&link) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if fs::read_link(&link)? == ccache_dir {
+                    return Ok(());
                 }
+                fs::remove_file(&link)?;
+            }
+            // A per-project cache from earlier builds; it's only a cache, so replace it.
+            Ok(_) => fs::remove_dir_all(&link)?,
+            Err(_) => {}
+        }
+        std::os::unix::fs::symlink(&ccache_dir, &link)?;
+        Ok(())
+    }
+
+    /// Runs `command` in the build sandbox with the given build options applied.
+    fn flatpak_build(&self, options: &BuildOptions, command: &[&str]) -> Result<()> {
+        let repo_dir = self.build_dirs.repo_dir();
+        let mut args: Vec<String> = vec!["build".to_string()];
+        args.extend(options.build_args.iter().cloned());
+        if let Some(ccache_dir) = self.ccache_dir() {
+            let ccache_dir = ccache_dir.canonicalize().unwrap_or(ccache_dir);
+            args.push(format!("--filesystem={}", ccache_dir.display()));
+            args.push(format!("--env=CCACHE_DIR={}", ccache_dir.display()));
+        }
+        args.extend(options.env_args());
+        if let Some(version) = &self.config.version {
+            args.push(format!("--env=VERSION={version}"));
+        }
+        args.push(repo_dir.to_str().unwrap().to_string());
+        args.extend(command.iter().map(|s| s.to_string()));
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        self.run_build_command(flatpak_bin(), &args)
+    }
+
+    /// Returns the directory of the active manifest, which module references are relative to.
+    fn manifest_dir(&self) -> PathBuf {
+        self.state
+            .active_manifest
+            .as_ref()
+            .and_then(|path| path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_else(|| self.state.base_dir.clone())
+    }
+
+    /// Returns the directory the application is built from: the `dir` source of its module,
+    /// relative to the manifest like flatpak-builder does, or the base directory without one.
+    fn app_source_dir(&self) -> Result<PathBuf> {
+        let modules = self.modules()?;
+        let dir_source = match modules.last() {
+            Some(Module::Object { sources, .. }) => sources
+                .iter()
+                .find(|source| source["type"] == "dir")
+                .and_then(|source| source["path"].as_str()),
+            _ => None,
+        };
+        Ok(match dir_source {
+            Some(path) => self.manifest_dir().join(path),
+            None => self.state.base_dir.clone(),
+        })
+    }
+
+    /// Returns the manifest modules, with references to module files loaded.
+    fn modules(&self) -> Result<Vec<Module>> {
+        let manifest = self.manifest.as_ref().unwrap();
+        manifest.resolved_modules(&self.manifest_dir())
+    }
+
+    /// Returns the name of the last module, which is the application itself.
+    fn app_module_name(&self) -> Result<String> {
+        self.modules()?
+            .last()
+            .map(|module| module.name().to_string())
+            .ok_or_else(|| anyhow::anyhow!("The manifest has no modules"))
+    }
+
+    fn build_application(&self) -> Result<()> {
+        let manifest = self.manifest.as_ref().unwrap();
+        if let Some(ccache_dir) = self.ccache_dir() {
+            fs::create_dir_all(&ccache_dir)?;
+        }
+
+        let modules = self.modules()?;
+        let Some(
+            app_module @ Module::Object {
+                config_opts,
+                build_commands,
+                post_install,
+                build_options,
+                ..
+            },
+        ) = modules.last()
+        else {
+            return Ok(());
+        };
+        let Some(buildsystem) = app_module.buildsystem() else {
+            return Ok(());
+        };
+
+        // Like flatpak-builder, the module's own build options, such as `--share=network` or
+        // its env, are merged over the manifest's for its build only.
+        let mut options = manifest.build_options.for_arch(self.target_arch());
+        if let Some(module_options) = build_options {
+            options.merge(&module_options.for_arch(self.target_arch()));
+        }
+        let mut config_opts_all = options.config_opts.clone();
+        config_opts_all.extend(config_opts.iter().flatten().cloned());
+
+        match buildsystem {
+            BuildSystem::Meson => self.run_meson(&options, &config_opts_all)?,
+            BuildSystem::CMake => self.run_cmake(&options, &config_opts_all)?,
+            BuildSystem::Simple => self.run_simple(&options, build_commands.as_ref())?,
+            BuildSystem::Autotools => self.run_autotools(&options, &config_opts_all)?,
+        }
+        if let Some(post_install) = post_install {
+            for command in post_install {
+                let args: Vec<&str> = command.split_whitespace().collect();
+                self.run_build_command(args[0], &args[1..])?;
             }
         }
 
         Ok(())
     }
 
-    fn run_meson(&self, repo_dir_str: &str, config_opts: Option<&Vec<String>>) -> Result<()> {
+    fn run_meson(&self, options: &BuildOptions, config_opts: &[String]) -> Result<()> {
         let build_dir = self.build_dirs.build_subdir();
         let build_dir_str = build_dir.to_str().unwrap();
-        let mut meson_args = vec!["build", repo_dir_str, "meson", "setup"];
-        if let Some(opts) = config_opts {
-            meson_args.extend(opts.iter().map(|s| s.as_str()));
-        }
-        meson_args.extend(&["--prefix=/app", build_dir_str]);
-        run_command("flatpak", &meson_args, Some(self.state.base_dir.as_path()))?;
-        run_command(
-            "flatpak",
-            &["build", repo_dir_str, "ninja", "-C", build_dir_str],
-            Some(self.state.base_dir.as_path()),
-        )?;
-        run_command(
-            "flatpak",
-            &[
-                "build",
-                repo_dir_str,
-                "meson",
-                "install",
-                "-C",
-                build_dir_str,
-            ],
-            Some(self.state.base_dir.as_path()),
-        )
+        let mut meson_args = vec!["meson", "setup"];
+        // Meson rejects the build type given twice, so the manifest's one wins outright.
+        let sets_buildtype = config_opts
+            .iter()
+            .any(|opt| opt.starts_with("--buildtype") || opt.starts_with("-Dbuildtype"));
+        if self.config.debug && !sets_buildtype {
+            meson_args.push("--buildtype=debug");
+        }
+        meson_args.extend(config_opts.iter().map(|s| s.as_str()));
+        let prefix = format!(
+            "--prefix={}",
+            self.manifest.as_ref().unwrap().install_prefix()
+        );
+        meson_args.extend(&[prefix.as_str(), build_dir_str]);
+        self.flatpak_build(options, &meson_args)?;
+        let jobs_args = self.jobs_args();
+        let mut ninja_args = vec!["ninja", "-C", build_dir_str];
+        ninja_args.extend(jobs_args.iter().map(|s| s.as_str()));
+        self.flatpak_build(options, &ninja_args)?;
+        self.flatpak_build(options, &["meson", "install", "-C", build_dir_str])
     }
 
-    fn run_cmake(&self, repo_dir_str: &str, config_opts: Option<&Vec<String>>) -> Result<()> {
+    fn run_cmake(&self, options: &BuildOptions, config_opts: &[String]) -> Result<()> {
         let build_dir = self.build_dirs.build_subdir();
         let build_dir_str = build_dir.to_str().unwrap();
         let b_flag = format!("-B{build_dir_str}");
+        let install_prefix = format!(
+            "-DCMAKE_INSTALL_PREFIX={}",
+            self.manifest.as_ref().unwrap().install_prefix()
+        );
         let mut cmake_args = vec![
-            "build",
-            repo_dir_str,
             "cmake",
             "-G",
             "Ninja",
             &b_flag,
             "-DCMAKE_EXPORT_COMPILE_COMMANDS=1",
-            "-DCMAKE_BUILD_TYPE=RelWithDebInfo",
-            "-DCMAKE_INSTALL_PREFIX=/app",
+            if self.config.debug {
+                "-DCMAKE_BUILD_TYPE=Debug"
+            } else {
+                "-DCMAKE_BUILD_TYPE=RelWithDebInfo"
+            },
+            &install_prefix,
         ];
-        if let Some(opts) = config_opts {
-            cmake_args.extend(opts.iter().map(|s| s.as_str()));
-        }
+        // Later definitions win, so the manifest's build type overrides ours.
+        cmake_args.extend(config_opts.iter().map(|s| s.as_str()));
         cmake_args.push(".");
-        run_command("flatpak", &cmake_args, Some(self.state.base_dir.as_path()))?;
-        run_command(
-            "flatpak",
-            &["build", repo_dir_str, "ninja", "-C", build_dir_str],
-            Some(self.state.base_dir.as_path()),
-        )?;
-        run_command(
-            "flatpak",
-            &[
-                "build",
-                repo_dir_str,
-                "ninja",
-                "-C",
-                build_dir_str,
-                "install",
-            ],
-            Some(self.state.base_dir.as_path()),
-        )
+        self.flatpak_build(options, &cmake_args)?;
+        let jobs_args = self.jobs_args();
+        let mut ninja_args = vec!["ninja", "-C", build_dir_str];
+        ninja_args.extend(jobs_args.iter().map(|s| s.as_str()));
+        self.flatpak_build(options, &ninja_args)?;
+        self.flatpak_build(options, &["ninja", "-C", build_dir_str, "install"])
     }
 
-    fn run_simple(&self, repo_dir_str: &str, build_commands: Option<&Vec<String>>) -> Result<()> {
+    fn run_simple(
+        &self,
+        options: &BuildOptions,
+        build_commands: Option<&Vec<String>>,
+    ) -> Result<()> {
         if let Some(commands) = build_commands {
             for command in commands {
-                let mut args = vec!["build", repo_dir_str];
-                args.extend(command.split_whitespace());
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+                let args: Vec<&str> = command.split_whitespace().collect();
+                self.flatpak_build(options, &args)?;
             }
         }
         Ok(())
     }
 
-    fn run_autotools(&self, repo_dir_str: &str, config_opts: Option<&Vec<String>>) -> Result<()> {
-        let mut autotools_args = vec!["build", repo_dir_str, "./configure", "--prefix=/app"];
-        if let Some(opts) = config_opts {
-            autotools_args.extend(opts.iter().map(|s| s.as_str()));
+    fn run_autotools(&self, options: &BuildOptions, config_opts: &[String]) -> Result<()> {
+        let prefix = format!(
+            "--prefix={}",
+            self.manifest.as_ref().unwrap().install_prefix()
+        );
+        let mut autotools_args = vec!["./configure", prefix.as_str()];
+        autotools_args.extend(config_opts.iter().map(|s| s.as_str()));
+        self.flatpak_build(options, &autotools_args)?;
+        let jobs_args = self.jobs_args();
+        let mut make_args = vec!["make"];
+        make_args.extend(jobs_args.iter().map(|s| s.as_str()));
+        self.flatpak_build(options, &make_args)?;
+        self.flatpak_build(options, &["make", "install"])
+    }
+
+    /// Returns the flags shared by every flatpak-builder run that builds dependencies.
+    fn dependency_build_args(&self, disable_download: bool) -> Vec<String> {
+        let mut args = vec!["--force-clean".to_string(), "--disable-updates".to_string()];
+        if disable_download {
+            args.push("--disable-download".to_string());
         }
-        run_command(
-            "flatpak",
-            &autotools_args,
-            Some(self.state.base_dir.as_path()),
-        )?;
-        run_command(
-            "flatpak",
-            &["build", repo_dir_str, "make"],
-            Some(self.state.base_dir.as_path()),
-        )?;
-        run_command(
-            "flatpak",
-            &["build", repo_dir_str, "make", "install"],
+        args.push("--build-only".to_string());
+        if self.config.ccache {
+            args.push("--ccache".to_string());
+        }
+        if self.config.keep_build_dirs {
+            args.push("--keep-build-dirs".to_string());
+        }
+        args.extend(self.arch_arg());
+        if let Some(jobs) = self.config.jobs {
+            args.push(format!("--jobs={jobs}"));
+        }
+        args.extend(self.config.builder_args.iter().cloned());
+        args
+    }
+
+    /// Returns the dependency modules marked `x-flatplay-independent`, if more than one module
+    /// may be built at a time. They are built on their own instead of in the manifest order.
+    fn independent_modules(&self) -> Result<Vec<String>> {
+        if self.config.max_parallel_modules.unwrap_or(1) < 2 {
+            return Ok(Vec::new());
+        }
+        let modules = self.modules()?;
+        let dependencies = &modules[..modules.len().saturating_sub(1)];
+        Ok(dependencies
+            .iter()
+            .filter(|module| module.is_independent())
+            .map(|module| module.name().to_string())
+            .collect())
+    }
+
+    /// Builds an independent module alone, into its own dir with its own flatpak-builder state.
+    /// Its sources are downloaded there if `update-dependencies` hasn't done it yet.
+    fn build_independent_module(&self, manifest_path: &Path, name: &str) -> Result<()> {
+        let builder_manifest =
+            filtered_builder_manifest(manifest_path, Some(ModuleFilter::Only(name)))?;
+        let module_dir = self.build_dirs.independent_modules_dir().join(name);
+        let mut args = self.dependency_build_args(false);
+        args.extend([
+            format!("--state-dir={}", module_dir.join("state").to_str().unwrap()),
+            module_dir.join("build").to_str().unwrap().to_string(),
+            builder_manifest.path().to_str().unwrap().to_string(),
+        ]);
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        self.runner.flatpak_builder(
+            &args_str,
+            &self.context_env(),
             Some(self.state.base_dir.as_path()),
+            self.command_timeout(),
         )
     }
 
+    /// Builds the independent modules, up to `--max-parallel-modules` at a time, then copies
+    /// them into the repo dir one after another.
+    fn build_independent_modules(&self, modules: &[String]) -> Result<()> {
+        if modules.is_empty() {
+            return Ok(());
+        }
+        let manifest_path = self.state.active_manifest.as_deref().unwrap();
+        let max_parallel = self.config.max_parallel_modules.unwrap_or(1) as usize;
+        println!(
+            "{} {} independent modules, {} at a time...",
+            "Building".bold(),
+            modules.len(),
+            max_parallel.min(modules.len())
+        );
+
+        let next = AtomicUsize::new(0);
+        let failures = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..max_parallel.min(modules.len()) {
+                scope.spawn(|| {
+                    while let Some(name) = modules.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if let Err(e) = self.build_independent_module(manifest_path, name) {
+                            failures.lock().unwrap().push(format!("{name}: {e}"));
+                        }
+                    }
+                });
+            }
+        });
+        let failures = failures.into_inner().unwrap();
+        if !failures.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Failed to build independent modules: {}",
+                failures.join(", ")
+            ));
+        }
+
+        // They all install into the same files dir, so they're copied one after another.
+        let files_dir = self.build_dirs.files_dir();
+        for name in modules {
+            let module_files = self
+                .build_dirs
+                .independent_modules_dir()
+                .join(name)
+                .join("build/files/.");
+            self.run_command(
+                "cp",
+                &[
+                    "-a",
+                    module_files.to_str().unwrap(),
+                    files_dir.to_str().unwrap(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
     fn build_dependencies(&mut self) -> Result<()> {
         println!("{}", "Building dependencies...".bold());
-        let manifest = self.manifest.as_ref().unwrap();
+        self.prepare_ccache_dir()?;
+        let independent_modules = self.independent_modules()?;
         let manifest_path = self.state.active_manifest.as_ref().unwrap();
+        let builder_manifest = if independent_modules.is_empty() {
+            builder_manifest(manifest_path)?
+        } else {
+            filtered_builder_manifest(
+                manifest_path,
+                Some(ModuleFilter::Except(&independent_modules)),
+            )?
+        };
         let repo_dir = self.build_dirs.repo_dir();
         let state_dir = self.build_dirs.flatpak_builder_dir();
-        flatpak_builder(
-            &[
-                "--ccache",
-                "--force-clean",
-                "--disable-updates",
-                "--disable-download",
-                "--build-only",
-                "--keep-build-dirs",
-                &format!("--state-dir={}", state_dir.to_str().unwrap()),
-                &format!(
-                    "--stop-at={}",
-                    match manifest.modules.last().unwrap() {
-                        Module::Object { name, .. } => name,
-                        Module::Reference(s) => s,
-                    }
-                ),
-                repo_dir.to_str().unwrap(),
-                manifest_path.to_str().unwrap(),
-            ],
+        let mut args = self.dependency_build_args(true);
+        args.extend([
+            format!("--state-dir={}", state_dir.to_str().unwrap()),
+            format!("--stop-at={}", self.app_module_name()?),
+            repo_dir.to_str().unwrap().to_string(),
+            builder_manifest.path().to_str().unwrap().to_string(),
+        ]);
+
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+        let start = Instant::now();
+        self.runner.flatpak_builder(
+            &args_str,
+            &self.context_env(),
             Some(self.state.base_dir.as_path()),
+            self.command_timeout(),
         )?;
+        self.build_independent_modules(&independent_modules)?;
+        self.state
+            .last_build_durations
+            .insert(BUILD_DEPENDENCIES_STEP.to_string(), start.elapsed());
+        self.state.module_hashes = self.dependency_hashes()?.into_iter().collect();
         self.state.dependencies_built = true;
         self.state.save()
     }
@@ -284,105 +784,761 @@ impl<'a> FlatpakManager<'a> {
     pub fn update_dependencies(&mut self) -> Result<()> {
         println!("{}", "Updating dependencies...".bold());
 
-        let manifest = self.manifest.as_ref().unwrap();
         let manifest_path = self.state.active_manifest.as_ref().unwrap();
+        let builder_manifest = builder_manifest(manifest_path)?;
         let repo_dir = self.build_dirs.repo_dir();
         let state_dir = self.build_dirs.flatpak_builder_dir();
-        flatpak_builder(
-            &[
-                "--ccache",
-                "--force-clean",
-                "--disable-updates",
-                "--download-only",
-                &format!("--state-dir={}", state_dir.to_str().unwrap()),
-                &format!(
-                    "--stop-at={}",
-                    match manifest.modules.last().unwrap() {
-                        Module::Object { name, .. } => name,
-                        Module::Reference(s) => s,
-                    }
-                ),
-                repo_dir.to_str().unwrap(),
-                manifest_path.to_str().unwrap(),
-            ],
+        let mut args: Vec<String> = [
+            "--ccache",
+            "--force-clean",
+            "--disable-updates",
+            "--download-only",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        args.extend(self.arch_arg());
+        args.extend(self.config.builder_args.iter().cloned());
+        args.extend([
+            format!("--state-dir={}", state_dir.to_str().unwrap()),
+            format!("--stop-at={}", self.app_module_name()?),
+            repo_dir.to_str().unwrap().to_string(),
+            builder_manifest.path().to_str().unwrap().to_string(),
+        ]);
+
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+        let start = Instant::now();
+        self.runner.flatpak_builder(
+            &args_str,
+            &self.context_env(),
             Some(self.state.base_dir.as_path()),
+            self.command_timeout(),
         )?;
+        self.state
+            .last_build_durations
+            .insert(UPDATE_DEPENDENCIES_STEP.to_string(), start.elapsed());
+        self.state.dependencies_hash = Some(self.dependencies_definition_hash()?);
         self.state.dependencies_updated = true;
         self.state.save()
     }
 
-    pub fn build(&mut self) -> Result<()> {
-        if self.manifest.is_none() {
-            println!(
-                "{}",
-                "No manifest selected. Please run `select-manifest` first.".yellow()
-            );
-            return Ok(());
-        }
+    /// Returns the name of the module following the dependency `module`, which flatpak-builder
+    /// has to `--stop-at` for `module` to be built.
+    fn module_after(&self, module: &str) -> Result<String> {
+        let modules = self.modules()?;
+        let names: Vec<&str> = modules.iter().map(|m| m.name()).collect();
+        let Some(index) = names.iter().position(|name| *name == module) else {
+            return Err(anyhow::anyhow!(
+                "Module {} not found. Available modules: {}",
+                module,
+                names.join(", ")
+            ));
+        };
+        let Some(next_module) = names.get(index + 1) else {
+            return Err(anyhow::anyhow!(
+                "{} is the application module, which is built from the project directory.",
+                module
+            ));
+        };
+        Ok(next_module.to_string())
+    }
 
+    /// Builds the dependencies up to and including `module` into the repo dir, without the
+    /// application. As the repo then only has part of the dependencies, the next full build
+    /// builds them all again.
+    pub fn build_up_to(&mut self, module: &str) -> Result<()> {
+        let next_module = self.module_after(module)?;
+        self.invalidate_changed_sources()?;
         if !self.state.dependencies_updated {
             self.update_dependencies()?;
         }
-        if !self.state.dependencies_built {
-            self.build_dependencies()?;
-        }
-        self.build_application()?;
-        self.state.application_built = true;
-        self.state.save()
-    }
 
-    pub fn build_and_run(&mut self) -> Result<()> {
-        self.build()?;
-        self.run()
-    }
+        println!(
+            "{} {}...",
+            "Building dependencies up to".bold(),
+            module.bold()
+        );
+        self.prepare_ccache_dir()?;
+        let manifest_path = self.state.active_manifest.as_ref().unwrap();
+        let builder_manifest = builder_manifest(manifest_path)?;
+        let repo_dir = self.build_dirs.repo_dir();
+        let state_dir = self.build_dirs.flatpak_builder_dir();
+        let mut args = self.dependency_build_args(true);
+        args.extend([
+            format!("--state-dir={}", state_dir.to_str().unwrap()),
+            format!("--stop-at={next_module}"),
+            repo_dir.to_str().unwrap().to_string(),
+            builder_manifest.path().to_str().unwrap().to_string(),
+        ]);
 
-    pub fn stop(&mut self) -> Result<()> {
-        kill_process_group(self.state)
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        self.state.dependencies_built = false;
+        self.state.application_built = false;
+        self.state.save()?;
+        self.runner
+            .flatpak_builder(
+                &args_str,
+                &self.context_env(),
+                Some(self.state.base_dir.as_path()),
+                self.command_timeout(),
+            )
+            .map_err(Error::BuildFailed)?;
+        println!(
+            "\n{} Built dependencies up to {}.",
+            "✔".green().bold(),
+            module
+        );
+        Ok(())
     }
 
-    pub fn run(&self) -> Result<()> {
-        if !self.state.application_built {
-            println!(
-                "{}",
-                "Application not built. Please run `build` first.".yellow()
-            );
-            return Ok(());
-        }
-        let manifest = self.manifest.as_ref().unwrap();
-        let repo_dir = self.build_dirs.repo_dir();
+    /// Builds the dependencies up to and including `module` and prints where its extracted and
+    /// patched sources are. They are kept even when the module fails to build.
+    pub fn checkout(&self, module: &str) -> Result<()> {
+        let next_module = self.module_after(module)?;
 
+        println!("{} {}...", "Checking out".bold(), module.bold());
+        self.prepare_ccache_dir()?;
+        let manifest_path = self.state.active_manifest.as_ref().unwrap();
+        let builder_manifest = builder_manifest(manifest_path)?;
+        let checkout_dir = self.build_dirs.checkout_dir();
+        let state_dir = self.build_dirs.flatpak_builder_dir();
         let mut args: Vec<String> = [
-            "build",
-            "--with-appdir",
-            "--allow=devel",
-            "--talk-name=org.freedesktop.portal.*",
-            "--talk-name=org.a11y.Bus",
+            "--force-clean",
+            "--disable-updates",
+            "--build-only",
+            "--keep-build-dirs",
         ]
         .iter()
         .map(|s| s.to_string())
         .collect();
+        if self.config.ccache {
+            args.push("--ccache".to_string());
+        }
+        args.extend(self.arch_arg());
+        if let Some(jobs) = self.config.jobs {
+            args.push(format!("--jobs={jobs}"));
+        }
+        args.extend(self.config.builder_args.iter().cloned());
+        args.extend([
+            format!("--state-dir={}", state_dir.to_str().unwrap()),
+            format!("--stop-at={next_module}"),
+            checkout_dir.to_str().unwrap().to_string(),
+            builder_manifest.path().to_str().unwrap().to_string(),
+        ]);
 
-        args.extend(
-            get_host_env()
-                .into_iter()
-                .map(|(key, value)| format!("--env={key}={value}")),
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        let result = self.runner.flatpak_builder(
+            &args_str,
+            &self.context_env(),
+            Some(self.state.base_dir.as_path()),
+            self.command_timeout(),
         );
 
-        args.extend(get_a11y_bus_args());
+        // flatpak-builder links the module name to its latest build dir.
+        let module_dir = state_dir.join("build").join(module);
+        if module_dir.exists() {
+            println!(
+                "\n{} {}",
+                "Sources:".bold(),
+                module_dir.canonicalize().unwrap_or(module_dir).display()
+            );
+        }
+        result
+    }
 
-        args.extend(manifest.finish_args.clone());
+    pub fn build(&mut self) -> Result<()> {
+        if self.manifest.is_none() {
+            println!(
+                "{}",
+                "No manifest selected. Please run `select-manifest` first.".yellow()
+            );
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let result = self.build_all();
+        if self.config.notify {
+            self.notify_build_finished(result.is_ok());
+        }
+        // Keep errors with their own exit code, such as missing runtimes.
+        let modules_built = result.map_err(|e| match e.downcast::<Error>() {
+            Ok(err) => err.into(),
+            Err(e) => anyhow::Error::from(Error::BuildFailed(e)),
+        })?;
+        self.print_build_summary(modules_built, start.elapsed());
+        self.run_hooks("post_build", &self.config.post_build)?;
+        if self.config.prune {
+            self.prune_build_dirs()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the app build directory and the flatpak-builder state, keeping the initialized
+    /// repo, so the app still runs. The next build starts those steps from scratch.
+    fn prune_build_dirs(&self) -> Result<()> {
+        let mut freed = 0;
+        for dir in [
+            self.build_dirs.build_subdir(),
+            self.build_dirs.flatpak_builder_dir(),
+            self.build_dirs.independent_modules_dir(),
+        ] {
+            if dir.exists() {
+                freed += dir_size(&dir);
+                fs::remove_dir_all(&dir)?;
+            }
+        }
+        println!(
+            "{} Pruned build directories, freed {}.",
+            "✔".green(),
+            format_size(freed)
+        );
+        Ok(())
+    }
+
+    /// Prints the final line of a successful build, so it stands out from the builder output.
+    fn print_build_summary(&self, modules_built: usize, elapsed: Duration) {
+        let manifest = self.manifest.as_ref().unwrap();
+        let modules = if modules_built == 1 {
+            "1 module".to_string()
+        } else {
+            format!("{modules_built} modules")
+        };
+        println!(
+            "\n{} {}",
+            "✔".green().bold(),
+            format!(
+                "Built {} ({}) in {} into {}",
+                manifest.id,
+                modules,
+                format_duration(elapsed),
+                self.build_dirs.repo_dir().display()
+            )
+            .green()
+            .bold()
+        );
+    }
+
+    /// Returns the input hash of every dependency module, that is all but the last one.
+    fn dependency_hashes(&self) -> Result<Vec<(String, String)>> {
+        let modules = self.modules()?;
+        let manifest_dir = self.manifest_dir().canonicalize()?;
+        let build_dir = self.build_dirs.build_dir();
+        let exclude = vec![build_dir.canonicalize().unwrap_or(build_dir)];
+
+        let dependencies = &modules[..modules.len().saturating_sub(1)];
+        dependencies
+            .iter()
+            .map(|module| {
+                let hash = module_input_hash(module, &manifest_dir, &exclude)?;
+                Ok((module.name().to_string(), hash))
+            })
+            .collect()
+    }
+
+    /// Returns the definition hash of the dependency modules, that is all but the last one.
+    fn dependencies_definition_hash(&self) -> Result<String> {
+        let modules = self.modules()?;
+        modules_definition_hash(&modules[..modules.len().saturating_sub(1)])
+    }
+
+    /// Marks the dependencies for downloading and building again if their definitions, such as
+    /// a source URL, changed since they were downloaded.
+    fn invalidate_changed_sources(&mut self) -> Result<()> {
+        if !self.state.dependencies_updated {
+            return Ok(());
+        }
+        // State from before the hash was recorded can't tell, so it is trusted.
+        let Some(recorded) = &self.state.dependencies_hash else {
+            return Ok(());
+        };
+        if *recorded == self.dependencies_definition_hash()? {
+            return Ok(());
+        }
+        println!(
+            "{} Dependency sources changed since they were downloaded, updating them.",
+            "⚠".yellow()
+        );
+        self.state.dependencies_updated = false;
+        self.state.dependencies_built = false;
+        Ok(())
+    }
+
+    /// Marks the dependencies for rebuilding if any of their local inputs changed since they were
+    /// built. flatpak-builder can't start at a given module, but its cache reuses every module
+    /// before the first changed one. Nothing needs downloading again; definition changes are
+    /// left to `invalidate_changed_sources`.
+    fn invalidate_changed_dependencies(&mut self) -> Result<()> {
+        // State from before the hashes were recorded can't tell, so it is trusted.
+        if !self.state.dependencies_built || self.state.module_hashes.is_empty() {
+            return Ok(());
+        }
+        let hashes = self.dependency_hashes()?;
+        if let Some(name) = first_changed_module(&hashes, &self.state.module_hashes) {
+            println!(
+                "{} Module {} changed since the last build, rebuilding dependencies from it.",
+                "⚠".yellow(),
+                name.bold()
+            );
+            self.state.dependencies_built = false;
+        }
+        Ok(())
+    }
+
+    /// Builds whatever isn't built yet and returns the number of modules built.
+    fn build_all(&mut self) -> Result<usize> {
+        self.invalidate_changed_sources()?;
+        self.invalidate_changed_dependencies()?;
+        if !self.state.dependencies_updated {
+            self.update_dependencies()?;
+        }
+        let mut modules_built = 1;
+        // With `--keep-going`, failures are collected and reported once everything was tried.
+        let mut failures = Vec::new();
+        if !self.state.dependencies_built {
+            match self.build_dependencies() {
+                Ok(()) => modules_built += self.modules()?.len().saturating_sub(1),
+                Err(e) if self.config.keep_going => failures.push(("dependencies".to_string(), e)),
+                Err(e) => return Err(e),
+            }
+        }
+        let start = Instant::now();
+        match self.build_application() {
+            Ok(()) if failures.is_empty() => {
+                self.state
+                    .last_build_durations
+                    .insert(BUILD_APPLICATION_STEP.to_string(), start.elapsed());
+                self.state.application_built = true;
+                self.state.save()?;
+            }
+            Ok(()) => {}
+            Err(e) if self.config.keep_going => failures.push((self.app_module_name()?, e)),
+            Err(e) => return Err(e),
+        }
+        if !failures.is_empty() {
+            eprintln!("\n{}", "Failed to build:".red().bold());
+            for (name, err) in &failures {
+                eprintln!("  {} {}: {}", "✘".red(), name.bold(), err);
+            }
+            let names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+            return Err(anyhow::anyhow!("Failed to build {}", names.join(", ")));
+        }
+        Ok(modules_built)
+    }
+
+    /// Sends a desktop notification about the build result, if `notify-send` is available.
+    fn notify_build_finished(&self, success: bool) {
+        if !self.runner.available("notify-send") {
+            return;
+        }
+        let manifest = self.manifest.as_ref().unwrap();
+        let (summary, body) = if success {
+            (
+                "Build finished",
+                format!("{} was built successfully.", manifest.id),
+            )
+        } else {
+            ("Build failed", format!("{} failed to build.", manifest.id))
+        };
+        if let Err(e) = self.run_command("notify-send", &["--app-name=flatplay", summary, &body]) {
+            eprintln!("{} Failed to send notification: {}", "⚠".yellow(), e);
+        }
+    }
+
+    /// Cleans the build directory and builds everything again from scratch.
+    pub fn rebuild(&mut self) -> Result<()> {
+        self.clean(false)?;
+        self.state.reset();
+        self.state.save()?;
+        // Cleaning removed the initialized build directory.
+        self.init()?;
+        self.build()
+    }
+
+    pub fn rebuild_and_run(&mut self, options: &RunOptions) -> Result<()> {
+        self.rebuild()?;
+        self.run(options)
+    }
+
+    pub fn build_and_run(&mut self, options: &RunOptions) -> Result<()> {
+        self.build()?;
+        self.run(options)
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        kill_process_group(self.state)
+    }
+
+    /// Assembles the `flatpak` arguments used to run the application from the build repo.
+    /// Returns the `flatpak build` arguments that set up the sandbox the app runs in,
+    /// up to and including the repo directory.
+    fn sandbox_args(&self, options: &RunOptions) -> Result<Vec<String>> {
+        let manifest = self.manifest.as_ref().unwrap();
+        let repo_dir = self.build_dirs.repo_dir();
+
+        let data_dir = self.data_dir(options)?;
+        let mut permissions: Vec<String> = Vec::new();
+        // A custom data dir replaces the one in ~/.var/app rather than sitting next to it.
+        if data_dir.is_none() {
+            permissions.push("--with-appdir".to_string());
+        }
+        permissions.extend(
+            [
+                "--allow=devel",
+                "--talk-name=org.freedesktop.portal.*",
+                "--talk-name=org.a11y.Bus",
+            ]
+            .iter()
+            .map(|s| s.to_string()),
+        );
+
+        let display_backend = options.display_backend();
+        let host_env: Vec<(String, String)> = if options.env_passthrough_all {
+            eprintln!(
+                "{} Forwarding the whole host environment to the app. Only use this for debugging.",
+                "⚠".yellow()
+            );
+            passthrough_env(std::env::vars(), &self.config.env_passthrough_deny)
+        } else {
+            get_host_env(&self.config.forward_env).into_iter().collect()
+        };
+        permissions.extend(
+            host_env
+                .into_iter()
+                // The X11 backend must not see the Wayland display, nor a headless run the host's.
+                .filter(|(key, _)| {
+                    (display_backend != Some(DisplayBackend::X11) || key != "WAYLAND_DISPLAY")
+                        && (!options.headless || key != "DISPLAY")
+                })
+                .map(|(key, value)| format!("--env={key}={value}")),
+        );
+
+        if let Some(data_dir) = &data_dir {
+            permissions.extend(data_dir_args(data_dir));
+        }
+
+        // Later `--env` flags win, so the env file overrides forwarded host variables.
+        if let Some(env_file) = &options.env_file {
+            let content = fs::read_to_string(env_file)
+                .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", env_file, e))?;
+            let vars = parse_env_file(&content)
+                .map_err(|e| anyhow::anyhow!("Failed to parse {:?}: {}", env_file, e))?;
+            permissions.extend(
+                vars.into_iter()
+                    .map(|(key, value)| format!("--env={key}={value}")),
+            );
+        }
+
+        if !options.no_a11y {
+            permissions.extend(get_a11y_bus_args());
+        }
+
+        if !options.no_device {
+            permissions.extend(get_device_args());
+        }
+
+        permissions.extend(manifest.finish_args.clone());
+        permissions.extend(options.permission_args());
+        if let Some(profiler) = options.profile {
+            permissions.extend(profiler.permission_args(&self.profiles_dir()?));
+        }
+        if let Some(output) = &options.strace {
+            // strace attaches with ptrace, which the sandbox only allows for development.
+            permissions.push("--allow=devel".to_string());
+            let output = self.strace_output(output.as_deref())?;
+            if let Some(dir) = output.as_deref().and_then(Path::parent) {
+                permissions.push(format!("--filesystem={}", dir.display()));
+            }
+        }
+
+        if let Some(backend) = display_backend {
+            permissions.extend(get_display_backend_args(backend));
+        }
+
+        let mut args = vec!["build".to_string()];
+        args.extend(dedup_args(permissions));
         args.push(repo_dir.to_str().unwrap().to_string());
-        args.push(manifest.command.clone());
-        if let Some(x_run_args) = &manifest.x_run_args {
-            args.extend(x_run_args.clone());
+        Ok(args)
+    }
+
+    /// Returns the data dir requested with `--clean-data` or `--persist-data`.
+    fn data_dir(&self, options: &RunOptions) -> Result<Option<PathBuf>> {
+        if options.clean_data {
+            Ok(Some(self.build_dirs.clean_data_dir()))
+        } else if let Some(dir) = &options.persist_data {
+            Ok(Some(std::path::absolute(dir)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the absolute dir profiler captures are written to.
+    fn profiles_dir(&self) -> Result<PathBuf> {
+        Ok(std::path::absolute(self.build_dirs.profiles_dir())?)
+    }
+
+    /// Returns the absolute file `--strace` writes to.
+    fn strace_output(&self, output: Option<&Path>) -> Result<Option<PathBuf>> {
+        output
+            .map(|output| Ok(std::path::absolute(output)?))
+            .transpose()
+    }
+
+    /// Creates the dirs the sandbox args refer to, right before the app is launched, so
+    /// `--dump-env`, `--print-run-command` or a run without a build leave the filesystem alone. The
+    /// `--clean-data` dir is emptied first, so every run starts from scratch.
+    fn prepare_run_dirs(&self, options: &RunOptions) -> Result<()> {
+        if let Some(data_dir) = self.data_dir(options)? {
+            if options.clean_data && data_dir.exists() {
+                fs::remove_dir_all(&data_dir)?;
+            }
+            for subdir in DATA_SUBDIRS {
+                fs::create_dir_all(data_dir.join(subdir))?;
+            }
+        }
+        if options.profile.is_some() {
+            fs::create_dir_all(self.profiles_dir()?)?;
+        }
+        if let Some(Some(output)) = &options.strace
+            && let Some(dir) = self
+                .strace_output(Some(output))?
+                .as_deref()
+                .and_then(Path::parent)
+        {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the command the app is launched under, from `--profile`, `--strace` or
+    /// `--wrapper`.
+    fn wrapper_args(&self, options: &RunOptions) -> Result<Vec<String>> {
+        if let Some(profiler) = options.profile {
+            let capture = profiler.capture_file(&self.profiles_dir()?);
+            return Ok(profiler.wrapper_args(&capture));
+        }
+        if let Some(output) = &options.strace {
+            return Ok(strace_args(
+                self.strace_output(output.as_deref())?.as_deref(),
+            ));
+        }
+        match &options.wrapper {
+            Some(wrapper) => shell_split(wrapper),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn run_args(&self, options: &RunOptions) -> Result<Vec<String>> {
+        let manifest = self.manifest.as_ref().unwrap();
+        let mut args = self.sandbox_args(options)?;
+        args.extend(self.wrapper_args(options)?);
+        args.extend(manifest.run_command_args(&options.args)?);
+        Ok(args)
+    }
+
+    pub fn run(&self, options: &RunOptions) -> Result<()> {
+        if self.manifest.as_ref().unwrap().build_runtime {
+            return Err(anyhow::anyhow!(
+                "Runtimes can't be run. Use `shell` or `build-terminal` to try it out."
+            ));
+        }
+        if options.dump_env {
+            return self.dump_env(options);
+        }
+        let args = self.run_args(options)?;
+        let result = self.run_in_sandbox(&args, options);
+        // Only checked on failure, so a working run doesn't pay for it.
+        if result.is_err() {
+            self.print_missing_command_hint();
+        } else if let Some(profiler) = options.profile
+            && !options.print_run_command
+        {
+            println!(
+                "{} {}",
+                "Profile written to".bold(),
+                profiler.capture_file(&self.profiles_dir()?).display()
+            );
+        }
+        result
+    }
+
+    /// Runs the installed release of the app with `flatpak run`, to compare it with the
+    /// development build. The manifest `x-run-args` and the permission options still apply.
+    pub fn run_installed(&self, options: &RunOptions) -> Result<()> {
+        let manifest = self.manifest.as_ref().unwrap();
+        let installation_arg = self.installation_arg();
+        // The branch the app is exported to, so the release it is compared with is the same one.
+        let app_ref = match self.branch() {
+            Some(branch) => format!("{}//{}", manifest.id, branch),
+            None => manifest.id.clone(),
+        };
+        let mut info_args = vec!["info"];
+        info_args.extend(installation_arg.as_deref());
+        info_args.push(&app_ref);
+        if !self.runner.succeeds(flatpak_bin(), &info_args) {
+            return Err(anyhow::anyhow!(
+                "{} is not installed. Install it with `{} install {}`.",
+                app_ref,
+                flatpak_bin(),
+                app_ref
+            ));
+        }
+
+        let mut args = vec!["run".to_string()];
+        args.extend(installation_arg);
+        args.extend(self.branch().map(|branch| format!("--branch={branch}")));
+        args.extend(options.permission_args());
+        if let Some(backend) = options.display_backend() {
+            args.extend(get_display_backend_args(backend));
         }
+        args.push(manifest.id.clone());
+        args.extend(manifest.run_args(&options.args));
+
+        if options.print_run_command {
+            print_launch_command(&args, options);
+            return Ok(());
+        }
+        self.launch(&args, options)
+    }
+
+    /// Launches the app with flatpak `args`, on a virtual display with `--headless`, where
+    /// the app's failure is reported with its own exit code.
+    fn launch(&self, args: &[String], options: &RunOptions) -> Result<()> {
+        let command = launch_command(args, options);
+        let args_str: Vec<&str> = command[1..].iter().map(|s| s.as_str()).collect();
+        let result = self.run_command(&command[0], &args_str);
+        if !options.headless {
+            return result;
+        }
+        result.map_err(|err| match err.downcast_ref::<Error>() {
+            Some(Error::CommandFailed(code)) => Error::AppExited(*code).into(),
+            _ => err,
+        })
+    }
+
+    /// Explains a failed run caused by a `command` that isn't installed in `/app/bin`,
+    /// listing the executables that are.
+    fn print_missing_command_hint(&self) {
+        let manifest = self.manifest.as_ref().unwrap();
+        let Some(command) = manifest.command.as_deref() else {
+            return;
+        };
+        let bin_dir = self.build_dirs.files_dir().join("bin");
+        let installed = match command.strip_prefix("/app/") {
+            Some(path) => self.build_dirs.files_dir().join(path).is_file(),
+            // Other absolute paths point into the runtime, which we can't inspect.
+            None if command.starts_with('/') => return,
+            None => bin_dir.join(command).is_file(),
+        };
+        if installed {
+            return;
+        }
+
+        eprintln!(
+            "{} The manifest command {} was not found in /app/bin.",
+            "⚠".yellow(),
+            command.bold()
+        );
+        let executables = list_executables(&bin_dir);
+        if executables.is_empty() {
+            eprintln!("No executables were installed to /app/bin.");
+        } else {
+            eprintln!("Executables in /app/bin: {}", executables.join(", "));
+        }
+    }
+
+    /// Returns the environment variables the sandbox gets, forwarded from the host, the env
+    /// file, the accessibility bus and the manifest, with the value that wins for each.
+    fn sandbox_env(&self, options: &RunOptions) -> Result<BTreeMap<String, String>> {
+        Ok(self
+            .sandbox_args(options)?
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("--env=")?.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
+    }
+
+    /// Prints the sandbox environment as `KEY=VALUE` lines, sorted by key so it diffs well.
+    fn dump_env(&self, options: &RunOptions) -> Result<()> {
+        for (key, value) in self.sandbox_env(options)? {
+            println!("{key}={value}");
+        }
+        Ok(())
+    }
+
+    /// Opens a shell in the same sandbox the app runs in. Arguments after `--` go to the shell.
+    pub fn shell(&self, options: &RunOptions) -> Result<()> {
+        if options.dump_env {
+            return self.dump_env(options);
+        }
+        let mut args = self.sandbox_args(options)?;
+        args.push("bash".to_string());
+        args.extend(options.args.iter().cloned());
+        self.run_in_sandbox(&args, options)
+    }
+
+    fn run_in_sandbox(&self, args: &[String], options: &RunOptions) -> Result<()> {
+        if options.print_run_command {
+            print_launch_command(args, options);
+            return Ok(());
+        }
+
+        if !self.state.application_built {
+            println!(
+                "{}",
+                "Application not built. Please run `build` first.".yellow()
+            );
+            return Ok(());
+        }
+
+        self.prepare_run_dirs(options)?;
+        self.launch(args, options)
+    }
+
+    /// Runs `build-finish` on the repo copy, applying the manifest's finish-args and metadata.
+    fn finish_build(&self, finalized_repo_dir: &Path) -> Result<()> {
+        let manifest = self.manifest.as_ref().unwrap();
+        let mut args: Vec<String> = vec!["build-finish".to_string()];
+
+        args.extend(manifest.finish_args.clone());
+        args.extend(manifest.extension_args());
+        args.extend(manifest.extra_data_args(&self.manifest_dir())?);
+        args.extend(manifest.metadata_args());
+        if let Some(command) = &manifest.command {
+            args.push(format!("--command={command}"));
+        }
+        args.push(finalized_repo_dir.to_str().unwrap().to_string());
 
         let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
 
-        run_command("flatpak", &args_str, Some(self.state.base_dir.as_path()))
+        self.run_command(flatpak_bin(), &args_str)
+    }
+
+    /// Returns the `--subject` and `--body` of the exported commit, naming the version and the
+    /// git commit it was built from, when known.
+    fn export_metadata_args(&self) -> Vec<String> {
+        let manifest = self.manifest.as_ref().unwrap();
+        let commit = self.config.git_commit.as_deref();
+        let subject = match (&self.config.version, commit) {
+            (Some(version), _) => format!("{} {}", manifest.id, version),
+            (None, Some(commit)) => format!("{} {}", manifest.id, &commit[..commit.len().min(12)]),
+            (None, None) => return Vec::new(),
+        };
+        let mut args = vec![format!("--subject={subject}")];
+        args.extend(commit.map(|commit| format!("--body=Built from git commit {commit}")));
+        args
     }
 
-    pub fn export_bundle(&self) -> Result<()> {
+    /// Exports a `.flatpak` bundle. The finalized copy of the repo is removed afterwards
+    /// unless `keep_repo` is set; the exported ostree repo is always kept. With `repo_only`,
+    /// the export stops at the ostree repo, without a bundle. With `flatpakref_url`, a
+    /// `.flatpakref` installing the app from the ostree repo served at that URL is written too.
+    pub fn export_bundle(
+        &self,
+        keep_repo: bool,
+        repo_only: bool,
+        flatpakref_url: Option<&str>,
+    ) -> Result<()> {
         if !self.state.application_built {
             println!(
                 "{}",
@@ -401,58 +1557,187 @@ impl<'a> FlatpakManager<'a> {
         }
 
         // Copy repo
-        run_command(
+        self.run_command(
             "cp",
             &[
                 "-r",
                 repo_dir.to_str().unwrap(),
                 finalized_repo_dir.to_str().unwrap(),
             ],
-            Some(self.state.base_dir.as_path()),
         )?;
 
-        // Finalize build
-        let mut args: Vec<String> = vec!["build-finish".to_string()];
+        // Runtimes are built into `usr` rather than `files`.
+        let files_dir = if manifest.build_runtime {
+            finalized_repo_dir.join("usr")
+        } else {
+            finalized_repo_dir.join("files")
+        };
 
-        args.extend(manifest.finish_args.clone());
-        args.push(format!("--command={}", manifest.command));
-        args.push(finalized_repo_dir.to_str().unwrap().to_string());
+        // Apply the manifest cleanup patterns, as flatpak-builder would
+        let removed = apply_cleanup(&files_dir, &manifest.cleanup)?;
+        if !removed.is_empty() {
+            println!("{} Cleaned up {} path(s).", "✔".green(), removed.len());
+        }
 
-        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        // Apply the manifest renames, as flatpak-builder would
+        let share_dir = files_dir.join("share");
+        if let Some(desktop_file) = &manifest.rename_desktop_file {
+            rename_desktop_file(
+                &share_dir,
+                desktop_file,
+                &manifest.id,
+                manifest.rename_icon.as_deref(),
+            )?;
+        }
+        if let Some(icon) = &manifest.rename_icon {
+            rename_icon(&share_dir, icon, &manifest.id)?;
+        }
 
-        run_command("flatpak", &args_str, Some(self.state.base_dir.as_path()))?;
+        // A bundle holds a single ref, so it can't carry a separate Locale extension.
+        // Translations stay in the app, as with `separate-locales: false`.
+        if manifest.separate_locales && files_dir.join("share/locale").is_dir() {
+            println!(
+                "{}",
+                "Translations are kept in the bundle instead of a separate Locale extension."
+                    .dimmed()
+            );
+        }
+
+        // Finalize build. Like flatpak-builder, runtimes skip it, as it only sets up apps.
+        if !manifest.build_runtime {
+            self.finish_build(&finalized_repo_dir)?;
+        }
 
         // Export build
-        run_command(
-            "flatpak",
-            &[
-                "build-export",
-                ostree_dir.to_str().unwrap(),
-                finalized_repo_dir.to_str().unwrap(),
-            ],
-            Some(self.state.base_dir.as_path()),
-        )?;
+        let mut args: Vec<String> = vec!["build-export".to_string()];
+        if manifest.build_runtime {
+            args.push("--runtime".to_string());
+        }
+        args.extend(self.arch_arg());
+        args.extend(self.export_metadata_args());
+        args.push(ostree_dir.to_str().unwrap().to_string());
+        args.push(finalized_repo_dir.to_str().unwrap().to_string());
+        args.extend(self.branch().map(str::to_string));
+
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+        self.run_command(flatpak_bin(), &args_str)?;
 
         // Bundle build
-        run_command(
-            "flatpak",
-            &[
-                "build-bundle",
-                ostree_dir.to_str().unwrap(),
-                format!("{}.flatpak", manifest.id.clone()).as_str(),
-                manifest.id.clone().as_str(),
-            ],
-            Some(self.state.base_dir.as_path()),
-        )
+        if !repo_only {
+            let mut args: Vec<String> = vec!["build-bundle".to_string()];
+            if manifest.build_runtime {
+                args.push("--runtime".to_string());
+            }
+            args.extend(self.arch_arg());
+            args.push(ostree_dir.to_str().unwrap().to_string());
+            args.push(format!("{}.flatpak", manifest.id));
+            args.push(manifest.id.clone());
+            args.extend(self.branch().map(str::to_string));
+
+            let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+            self.run_command(flatpak_bin(), &args_str)?;
+        }
+
+        let absolute = |path: PathBuf| path.canonicalize().unwrap_or(path);
+        if keep_repo {
+            println!(
+                "{} {}",
+                "Finalized repo:".bold(),
+                absolute(finalized_repo_dir).display()
+            );
+        } else {
+            fs::remove_dir_all(&finalized_repo_dir)?;
+        }
+        println!(
+            "{} {}",
+            "OSTree repo:".bold(),
+            absolute(ostree_dir).display()
+        );
+        if !repo_only {
+            println!(
+                "{} {}",
+                "Bundle:".bold(),
+                absolute(self.state.base_dir.join(format!("{}.flatpak", manifest.id))).display()
+            );
+        }
+        if let Some(url) = flatpakref_url {
+            let path = self
+                .state
+                .base_dir
+                .join(format!("{}.flatpakref", manifest.id));
+            let content = flatpakref(&manifest.id, self.branch(), url, manifest.build_runtime);
+            fs::write(&path, content)?;
+            println!("{} {}", "Flatpakref:".bold(), absolute(path).display());
+        }
+        self.run_hooks("post_export", &self.config.post_export)
+    }
+
+    /// Runs the hook commands configured for a step with `sh`, stopping at the first failure.
+    fn run_hooks(&self, step: &str, hooks: &[String]) -> Result<()> {
+        for hook in hooks {
+            println!("{} {}", format!("Running {step} hook:").bold(), hook);
+            self.run_command("sh", &["-c", hook])
+                .map_err(|e| anyhow::anyhow!("The {} hook {:?} failed: {}", step, hook, e))?;
+        }
+        Ok(())
     }
 
-    pub fn clean(&mut self) -> Result<()> {
+    /// Removes the build directory, but for a selected remote manifest. A repo dir configured
+    /// outside of it may be shared with other checkouts, so it is only removed with `remove_repo`.
+    pub fn clean(&mut self, remove_repo: bool) -> Result<()> {
         let build_dir = self.build_dirs.build_dir();
         if fs::metadata(&build_dir).is_ok() {
+            let base_dir = self.state.base_dir.canonicalize()?;
+            if base_dir.starts_with(build_dir.canonicalize()?) {
+                return Err(anyhow::anyhow!(
+                    "Refusing to clean {:?}, which contains the project directory.",
+                    build_dir
+                ));
+            }
+            // A selected remote manifest is downloaded into the build dir, and stays selected.
+            // The trace log is kept too, so it still covers what `rebuild` ran before cleaning.
+            let mut kept_files = Vec::new();
+            let remote_manifest = self
+                .state
+                .active_manifest
+                .as_ref()
+                .filter(|path| path.starts_with(&build_dir));
+            for path in remote_manifest
+                .into_iter()
+                .cloned()
+                .chain([self.build_dirs.trace_file()])
+            {
+                if path.is_file() {
+                    let content = fs::read(&path)?;
+                    kept_files.push((path, content));
+                }
+            }
             fs::remove_dir_all(&build_dir)?;
+            for (path, content) in kept_files {
+                fs::create_dir_all(&build_dir)?;
+                fs::write(path, content)?;
+            }
             println!("{} Cleaned .flatplay directory.", "✔".green());
             self.state.reset();
         }
+        let repo_dir = self.build_dirs.repo_dir();
+        if self.build_dirs.is_repo_dir_external() && repo_dir.exists() {
+            if remove_repo {
+                fs::remove_dir_all(&repo_dir)?;
+                println!("{} Cleaned repo directory {:?}.", "✔".green(), repo_dir);
+                self.state.reset();
+            } else {
+                println!(
+                    "{}",
+                    format!(
+                        "Kept repo directory {repo_dir:?}, which is outside the build directory. Pass `--repo` to `clean` to remove it."
+                    )
+                    .dimmed()
+                );
+            }
+        }
         Ok(())
     }
 
@@ -466,11 +1751,29 @@ impl<'a> FlatpakManager<'a> {
         }
         let manifest = self.manifest.as_ref().unwrap();
         let sdk_id = format!("{}//{}", manifest.sdk, manifest.runtime_version);
-        run_command(
-            "flatpak",
-            &["run", "--command=bash", &sdk_id],
-            Some(self.state.base_dir.as_path()),
-        )
+        let mut args = vec!["run".to_string()];
+        args.extend(self.installation_arg());
+        args.extend(self.sdk_extension_path_arg());
+        args.extend(["--command=bash".to_string(), sdk_id]);
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        self.run_command(flatpak_bin(), &args_str)
+    }
+
+    /// Returns an `--env=PATH=...` argument putting the SDK extension tools in the `PATH`,
+    /// or nothing when the manifest uses no SDK extensions.
+    fn sdk_extension_path_arg(&self) -> Option<String> {
+        let manifest = self.manifest.as_ref().unwrap();
+        if manifest.sdk_extensions.is_empty() {
+            return None;
+        }
+        println!(
+            "{} {}",
+            "SDK extensions:".bold(),
+            manifest.sdk_extensions.join(", ")
+        );
+        let mut path = manifest.sdk_extension_bin_dirs();
+        path.extend(["/app/bin".to_string(), "/usr/bin".to_string()]);
+        Some(format!("--env=PATH={}", path.join(":")))
     }
 
     pub fn build_terminal(&self) -> Result<()> {
@@ -481,18 +1784,22 @@ impl<'a> FlatpakManager<'a> {
             );
             return Ok(());
         }
-        let manifest = self.manifest.as_ref().unwrap();
-        let _app_id = &manifest.id;
         let repo_dir = self.build_dirs.repo_dir();
-        run_command(
-            "flatpak",
-            &["build", repo_dir.to_str().unwrap(), "bash"],
-            Some(self.state.base_dir.as_path()),
-        )
+        let mut args = vec!["build".to_string()];
+        args.extend(self.sdk_extension_path_arg());
+        args.extend([repo_dir.to_str().unwrap().to_string(), "bash".to_string()]);
+        let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        self.run_command(flatpak_bin(), &args_str)
     }
 
     /// Manifest selection command endpoint.
-    pub fn select_manifest(&mut self, path: Option<PathBuf>) -> Result<()> {
+    /// `index` picks the Nth manifest (starting at 1) from the sorted list without prompting.
+    pub fn select_manifest(&mut self, path: Option<PathBuf>, index: Option<usize>) -> Result<()> {
+        if let Some(url) = path.as_ref().and_then(|p| p.to_str())
+            && is_manifest_url(url)
+        {
+            return self.select_remote_manifest(url);
+        }
         if let Some(path) = path {
             let manifest_path = if path.is_absolute() {
                 path
@@ -506,20 +1813,48 @@ impl<'a> FlatpakManager<'a> {
                 ));
             }
             let manifest = Manifest::from_file(&manifest_path)?;
-            return self.set_active_manifest(manifest_path, Some(manifest));
+            return self.set_active_manifest(manifest_path, manifest);
         }
 
         println!("{}", "Searching for manifest files...".bold());
-        let manifests = self.find_manifests()?;
+        let mut manifests = self.find_manifests()?;
 
         if manifests.is_empty() {
             println!("{}", "No manifest files found.".yellow());
             return Ok(());
         }
 
+        if let Some(index) = index {
+            if index == 0 || index > manifests.len() {
+                return Err(anyhow::anyhow!(
+                    "Invalid manifest index {}. Found {} manifest(s).",
+                    index,
+                    manifests.len()
+                ));
+            }
+            let (manifest_path, manifest) = manifests.swap_remove(index - 1);
+            return self.set_active_manifest(manifest_path, manifest);
+        }
+
+        let default_selection = manifests
+            .iter()
+            .position(|(p, _)| self.state.active_manifest.as_ref() == Some(p))
+            .unwrap_or(0);
+
+        if self.config.non_interactive {
+            let (manifest_path, manifest) = manifests.swap_remove(default_selection);
+            return self.set_active_manifest(manifest_path, manifest);
+        }
+
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow::anyhow!(
+                "Cannot prompt for a manifest without a terminal. Pass a path, `--index <N>`, `--first` or `--yes`."
+            ));
+        }
+
         let manifest_strings: Vec<String> = manifests
             .iter()
-            .map(|p| {
+            .map(|(p, _)| {
                 let path_str = p.to_str().unwrap().to_string();
                 if self.state.active_manifest.as_ref() == Some(p) {
                     format!("{} {}", "*".green().bold(), path_str)
@@ -529,11 +1864,6 @@ impl<'a> FlatpakManager<'a> {
             })
             .collect();
 
-        let default_selection = manifests
-            .iter()
-            .position(|p| self.state.active_manifest.as_ref() == Some(p))
-            .unwrap_or(0);
-
         let theme = ColorfulTheme::default();
         let selection = Select::with_theme(&theme)
             .with_prompt("Select a manifest")
@@ -541,27 +1871,77 @@ impl<'a> FlatpakManager<'a> {
             .default(default_selection)
             .interact()?;
 
-        self.set_active_manifest(manifests[selection].clone(), None)
+        let (manifest_path, manifest) = manifests.swap_remove(selection);
+        self.set_active_manifest(manifest_path, manifest)
+    }
+
+    /// Downloads a manifest and makes it the active one, always starting from a clean build.
+    /// The copy lives in the build directory, so module files and local sources next to the
+    /// original manifest are not available unless fetched separately.
+    fn select_remote_manifest(&mut self, url: &str) -> Result<()> {
+        let extension = remote_manifest_extension(url).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Remote manifest URLs must end in .json, .yaml or .yml: {}",
+                url
+            )
+        })?;
+        // Downloaded outside the build directory, which is cleaned before switching manifests.
+        let download = self
+            .state
+            .base_dir
+            .join(format!(".flatplay-download.{extension}"));
+        let download_str = download.to_str().unwrap();
+        self.run_command(
+            "curl",
+            &[
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--output",
+                download_str,
+                url,
+            ],
+        )?;
+        let manifest = match Manifest::from_file(&download) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let _ = fs::remove_file(&download);
+                return Err(anyhow::anyhow!("Invalid remote manifest: {}", e));
+            }
+        };
+
+        // Clean first, as it keeps the remote manifest that is still selected.
+        self.state.active_manifest = None;
+        self.clean(false)?;
+        let build_dir = self.build_dirs.build_dir();
+        fs::create_dir_all(&build_dir)?;
+        let manifest_path = build_dir.join(format!("remote-manifest.{extension}"));
+        fs::rename(&download, &manifest_path)?;
+
+        self.state.active_manifest = Some(manifest_path.clone());
+        self.state.save()?;
+        self.manifest = Some(manifest);
+        println!("{} {}", "Selected remote manifest:".green(), url);
+        println!(
+            "{} Module files and local sources it references are not downloaded.",
+            "⚠".yellow()
+        );
+        Ok(())
     }
 
     /// Sets the active manifest and updates the state.
-    fn set_active_manifest(
-        &mut self,
-        manifest_path: PathBuf,
-        manifest: Option<Manifest>,
-    ) -> Result<()> {
+    fn set_active_manifest(&mut self, manifest_path: PathBuf, manifest: Manifest) -> Result<()> {
         let should_clean = self.state.active_manifest.as_ref() != Some(&manifest_path);
         if should_clean {
             // Clean build directory and progress since manifest has changed.
-            self.clean()?;
+            self.clean(false)?;
 
             // Change active manifest in state.
             self.state.active_manifest = Some(manifest_path.clone());
             self.state.save()?;
         }
-        if let Some(manifest) = manifest {
-            self.manifest = Some(manifest);
-        }
+        self.manifest = Some(manifest);
         println!(
             "{} {:?}. You can now run `{}`.",
             "Selected manifest:".green(),
@@ -572,3 +1952,987 @@ impl<'a> FlatpakManager<'a> {
         Ok(())
     }
 }
+
+/// Prints the command line launching the app, quoted so it can be pasted into a shell.
+fn print_launch_command(args: &[String], options: &RunOptions) {
+    let command: Vec<String> = launch_command(args, options)
+        .iter()
+        .map(String::as_str)
+        .map(shell_quote)
+        .collect();
+    println!("{}", command.join(" "));
+}
+
+/// Returns the command line running flatpak with `args`, under `xvfb-run` with `--headless`.
+/// xvfb-run starts Xvfb on a free display number for flatpak to share with the sandbox, and
+/// exits with the app's exit code.
+fn launch_command(args: &[String], options: &RunOptions) -> Vec<String> {
+    let mut command = Vec::new();
+    if options.headless {
+        command.extend(["xvfb-run".to_string(), "--auto-servernum".to_string()]);
+    }
+    command.push(flatpak_bin().to_string());
+    command.extend(args.iter().cloned());
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::options::Profiler;
+
+    /// Records the commands it is asked to run instead of running them.
+    #[derive(Clone, Default)]
+    struct RecordingRunner {
+        calls: Arc<Mutex<Vec<Vec<String>>>>,
+        missing_runtimes: bool,
+        failing_builder: bool,
+    }
+
+    impl RecordingRunner {
+        fn record(&self, command: &str, args: &[&str]) {
+            let call = std::iter::once(command).chain(args.iter().copied());
+            self.calls
+                .lock()
+                .unwrap()
+                .push(call.map(str::to_string).collect());
+        }
+
+        /// Returns the recorded calls with the given first argument, like `build`.
+        fn calls_to(&self, subcommand: &str) -> Vec<Vec<String>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|call| call.get(1).is_some_and(|arg| arg == subcommand))
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run(
+            &self,
+            command: &str,
+            args: &[&str],
+            _env: &[(String, String)],
+            _working_dir: Option<&std::path::Path>,
+            _timeout: Option<Duration>,
+        ) -> Result<()> {
+            self.record(command, args);
+            Ok(())
+        }
+
+        fn flatpak_builder(
+            &self,
+            args: &[&str],
+            _env: &[(String, String)],
+            _working_dir: Option<&std::path::Path>,
+            _timeout: Option<Duration>,
+        ) -> Result<()> {
+            self.record("flatpak-builder", args);
+            if self.failing_builder {
+                return Err(anyhow::anyhow!("Command failed with exit code: 1"));
+            }
+            Ok(())
+        }
+
+        fn succeeds(&self, _command: &str, _args: &[&str]) -> bool {
+            !self.missing_runtimes
+        }
+
+        fn output(&self, _command: &str, _args: &[&str]) -> Option<String> {
+            None
+        }
+    }
+
+    fn project(buildsystem: &str) -> (tempfile::TempDir, Config) {
+        project_with_modules(&format!(
+            r#"{{
+                "name": "example",
+                "buildsystem": "{buildsystem}",
+                "config-opts": ["-Dprofile=devel"],
+                "sources": [{{ "type": "dir", "path": "." }}]
+            }}"#
+        ))
+    }
+
+    /// Creates a project whose manifest has the given comma-separated JSON modules.
+    fn project_with_modules(modules: &str) -> (tempfile::TempDir, Config) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("com.example.App.json"),
+            format!(
+                r#"{{
+                    "id": "com.example.App",
+                    "sdk": "org.gnome.Sdk",
+                    "runtime": "org.gnome.Platform",
+                    "runtime-version": "48",
+                    "command": "example",
+                    "modules": [{modules}]
+                }}"#
+            ),
+        )
+        .unwrap();
+        let config = Config {
+            manifest: Some(PathBuf::from("com.example.App.json")),
+            ..Default::default()
+        };
+        (dir, config)
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn init_runs_build_init() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner::default();
+        FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+
+        let repo_dir = dir.path().join(".flatplay/repo");
+        assert_eq!(
+            runner.calls_to("build-init"),
+            vec![strings(&[
+                flatpak_bin(),
+                "build-init",
+                repo_dir.to_str().unwrap(),
+                "com.example.App",
+                "org.gnome.Sdk",
+                "org.gnome.Platform",
+                "48",
+            ])]
+        );
+    }
+
+    #[test]
+    fn context_env_describes_the_active_manifest() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(RecordingRunner::default()))
+                .unwrap();
+
+        let manifest_path = dir.path().join("com.example.App.json");
+        assert_eq!(
+            manager.context_env(),
+            vec![
+                (
+                    "FLATPLAY_MANIFEST".to_string(),
+                    manifest_path.to_str().unwrap().to_string()
+                ),
+                ("FLATPLAY_APP_ID".to_string(), "com.example.App".to_string()),
+            ]
+        );
+
+        // Selecting another manifest changes what the following commands get.
+        let other_path = dir.path().join("com.example.Other.json");
+        let other = fs::read_to_string(&manifest_path)
+            .unwrap()
+            .replace("com.example.App", "com.example.Other");
+        fs::write(&other_path, other).unwrap();
+        manager
+            .select_manifest(Some(other_path.clone()), None)
+            .unwrap();
+        assert_eq!(
+            manager.context_env(),
+            vec![
+                (
+                    "FLATPLAY_MANIFEST".to_string(),
+                    other_path.to_str().unwrap().to_string()
+                ),
+                (
+                    "FLATPLAY_APP_ID".to_string(),
+                    "com.example.Other".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn clean_keeps_an_external_repo_dir_unless_asked() {
+        let (dir, mut config) = project("meson");
+        let shared = tempfile::tempdir().unwrap();
+        let repo_dir = shared.path().join("repo");
+        config.repo_dir = Some(repo_dir.clone());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        fs::create_dir_all(repo_dir.join("files")).unwrap();
+
+        manager.clean(false).unwrap();
+        assert!(repo_dir.is_dir());
+        manager.clean(true).unwrap();
+        assert!(!repo_dir.exists());
+    }
+
+    #[test]
+    fn init_only_removes_build_init_entries_from_an_external_repo_dir() {
+        let (dir, mut config) = project("meson");
+        let shared = tempfile::tempdir().unwrap();
+        config.repo_dir = Some(shared.path().to_path_buf());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        assert_eq!(runner.calls_to("build-init").len(), 1);
+        fs::create_dir_all(shared.path().join("var/lib")).unwrap();
+        fs::write(shared.path().join("notes.txt"), "keep me").unwrap();
+
+        assert!(manager.init().is_err());
+        assert!(shared.path().join("var/lib").is_dir());
+        assert!(shared.path().join("notes.txt").is_file());
+        assert_eq!(runner.calls_to("build-init").len(), 1);
+
+        // A dir holding only build-init leftovers is cleaned, but kept.
+        fs::remove_file(shared.path().join("notes.txt")).unwrap();
+        manager.init().unwrap();
+        assert!(!shared.path().join("var").exists());
+        assert!(shared.path().is_dir());
+        assert_eq!(runner.calls_to("build-init").len(), 2);
+    }
+
+    #[test]
+    fn clean_refuses_to_remove_the_project() {
+        let (dir, mut config) = project("meson");
+        config.build_dir = Some(PathBuf::from("."));
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(RecordingRunner::default()))
+                .unwrap();
+
+        assert!(manager.clean(false).is_err());
+        assert!(dir.path().join("com.example.App.json").is_file());
+    }
+
+    #[test]
+    fn clean_keeps_the_selected_remote_manifest() {
+        let (dir, config) = project("meson");
+        let remote_manifest = dir.path().join(".flatplay/remote-manifest.json");
+        fs::create_dir_all(dir.path().join(".flatplay/repo")).unwrap();
+        fs::copy(dir.path().join("com.example.App.json"), &remote_manifest).unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.active_manifest = Some(remote_manifest.clone());
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+
+        manager.rebuild().unwrap();
+        assert!(remote_manifest.is_file());
+        assert!(!runner.calls_to("build").is_empty());
+        assert_eq!(state.active_manifest, Some(remote_manifest));
+    }
+
+    #[test]
+    fn clean_keeps_the_trace_log() {
+        let (dir, config) = project("meson");
+        let trace_file = dir.path().join(".flatplay/trace.log");
+        fs::create_dir_all(dir.path().join(".flatplay/repo")).unwrap();
+        fs::write(&trace_file, "START flatpak build-init\n").unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(RecordingRunner::default()))
+                .unwrap();
+
+        manager.clean(false).unwrap();
+        assert!(!dir.path().join(".flatplay/repo").exists());
+        assert_eq!(
+            fs::read_to_string(&trace_file).unwrap(),
+            "START flatpak build-init\n"
+        );
+    }
+
+    #[test]
+    fn switching_arch_resets_the_build_progress() {
+        let (dir, mut config) = project("meson");
+        config.arch = Some("aarch64".to_string());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+
+        assert!(!state.dependencies_updated);
+        assert!(!state.dependencies_built);
+        assert!(!state.application_built);
+        assert_eq!(state.arch.as_deref(), Some("aarch64"));
+    }
+
+    #[test]
+    fn missing_runtimes_fail_init() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner {
+            missing_runtimes: true,
+            ..Default::default()
+        };
+        let Err(err) = FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone()))
+        else {
+            panic!("init succeeded without runtimes");
+        };
+        assert_eq!(error::exit_code(&err), 3);
+        assert!(runner.calls_to("build-init").is_empty());
+    }
+
+    #[test]
+    fn build_runs_meson() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build().unwrap();
+
+        let repo_dir = dir.path().join(".flatplay/repo");
+        let build_dir = dir.path().join(".flatplay/_build");
+        let (repo_dir, build_dir) = (repo_dir.to_str().unwrap(), build_dir.to_str().unwrap());
+        assert_eq!(
+            runner.calls_to("build"),
+            vec![
+                strings(&[
+                    flatpak_bin(),
+                    "build",
+                    repo_dir,
+                    "meson",
+                    "setup",
+                    "-Dprofile=devel",
+                    "--prefix=/app",
+                    build_dir,
+                ]),
+                strings(&[flatpak_bin(), "build", repo_dir, "ninja", "-C", build_dir]),
+                strings(&[
+                    flatpak_bin(),
+                    "build",
+                    repo_dir,
+                    "meson",
+                    "install",
+                    "-C",
+                    build_dir,
+                ]),
+            ]
+        );
+        assert!(state.application_built);
+    }
+
+    #[test]
+    fn keep_going_builds_the_app_after_dependencies_fail() {
+        let (dir, mut config) = project("meson");
+        config.keep_going = true;
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        let runner = RecordingRunner {
+            failing_builder: true,
+            ..Default::default()
+        };
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let err = manager.build().unwrap_err();
+
+        assert_eq!(error::exit_code(&err), 4);
+        assert_eq!(err.to_string(), "Failed to build dependencies");
+        assert_eq!(runner.calls_to("build").len(), 3);
+        assert!(!state.dependencies_built);
+        assert!(!state.application_built);
+    }
+
+    #[test]
+    fn changed_local_dependency_sources_rebuild_the_dependencies() {
+        let (dir, config) = project_with_modules(
+            r#"{ "name": "libfoo", "sources": [{ "type": "dir", "path": "libfoo" }] },
+               { "name": "example", "buildsystem": "simple" }"#,
+        );
+        fs::create_dir(dir.path().join("libfoo")).unwrap();
+        fs::write(dir.path().join("libfoo/foo.c"), "int foo;").unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build_dependencies().unwrap();
+
+        manager.invalidate_changed_dependencies().unwrap();
+        assert!(manager.state.dependencies_built);
+
+        fs::write(dir.path().join("libfoo/foo.c"), "int foo = 1;").unwrap();
+        manager.invalidate_changed_dependencies().unwrap();
+        assert!(!manager.state.dependencies_built);
+        // Local sources aren't downloaded, so the dependencies aren't updated again.
+        assert!(manager.state.dependencies_updated);
+    }
+
+    #[test]
+    fn dependency_hashes_cover_included_modules() {
+        let (dir, config) = project_with_modules(r#""modules/""#);
+        fs::create_dir(dir.path().join("modules")).unwrap();
+        fs::write(
+            dir.path().join("modules/a-libfoo.json"),
+            r#"{ "name": "libfoo" }"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("modules/b-example.json"),
+            r#"{ "name": "example", "buildsystem": "simple" }"#,
+        )
+        .unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(RecordingRunner::default()))
+                .unwrap();
+
+        let names: Vec<String> = manager
+            .dependency_hashes()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["libfoo"]);
+    }
+
+    #[test]
+    fn independent_modules_are_built_on_their_own() {
+        let (dir, mut config) = project_with_modules(
+            r#"{ "name": "libfoo", "x-flatplay-independent": true },
+               { "name": "libbar", "x-flatplay-independent": true },
+               { "name": "libbaz" },
+               { "name": "example", "buildsystem": "simple" }"#,
+        );
+        config.max_parallel_modules = Some(2);
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build_dependencies().unwrap();
+
+        let calls = runner.calls.lock().unwrap().clone();
+        let builder_calls: Vec<&Vec<String>> = calls
+            .iter()
+            .filter(|call| call[0] == "flatpak-builder")
+            .collect();
+        assert_eq!(builder_calls.len(), 3);
+        assert!(builder_calls[0].contains(&"--stop-at=example".to_string()));
+        assert!(builder_calls[0].contains(&"--disable-download".to_string()));
+        let independent_dir = dir.path().join(".flatplay/independent");
+        let mut module_builds: Vec<&String> = builder_calls[1..]
+            .iter()
+            .map(|call| &call[call.len() - 2])
+            .collect();
+        module_builds.sort();
+        assert_eq!(
+            module_builds,
+            [
+                independent_dir.join("libbar/build").to_str().unwrap(),
+                independent_dir.join("libfoo/build").to_str().unwrap(),
+            ]
+        );
+
+        let files_dir = dir.path().join(".flatplay/repo/files");
+        let copies: Vec<&Vec<String>> = calls.iter().filter(|call| call[0] == "cp").collect();
+        assert_eq!(
+            copies,
+            [
+                &strings(&[
+                    "cp",
+                    "-a",
+                    independent_dir
+                        .join("libfoo/build/files/.")
+                        .to_str()
+                        .unwrap(),
+                    files_dir.to_str().unwrap(),
+                ]),
+                &strings(&[
+                    "cp",
+                    "-a",
+                    independent_dir
+                        .join("libbar/build/files/.")
+                        .to_str()
+                        .unwrap(),
+                    files_dir.to_str().unwrap(),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn run_installed_runs_the_installed_app() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let options = RunOptions {
+            args: strings(&["--verbose"]),
+            ..Default::default()
+        };
+        manager.run_installed(&options).unwrap();
+
+        assert_eq!(
+            runner.calls_to("run"),
+            vec![strings(&[
+                flatpak_bin(),
+                "run",
+                "com.example.App",
+                "--verbose"
+            ])]
+        );
+    }
+
+    #[test]
+    fn profile_runs_the_app_under_the_profiler() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let options = RunOptions {
+            profile: Some(Profiler::Perf),
+            no_a11y: true,
+            ..Default::default()
+        };
+        manager.run(&options).unwrap();
+
+        let profiles_dir = dir.path().join(".flatplay/profiles");
+        let capture = profiles_dir.join("perf.data");
+        let run = &runner.calls_to("build")[0];
+        assert!(run.contains(&format!("--filesystem={}", profiles_dir.display())));
+        assert!(run.contains(&"--device=all".to_string()));
+        let command = &run[run.len() - 7..];
+        assert_eq!(
+            command,
+            strings(&[
+                "perf",
+                "record",
+                "--call-graph=dwarf",
+                "-o",
+                capture.to_str().unwrap(),
+                "--",
+                "example",
+            ])
+        );
+    }
+
+    #[test]
+    fn strace_traces_the_app_with_ptrace_allowed() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let trace_file = dir.path().join("traces/strace.log");
+        let options = RunOptions {
+            strace: Some(Some(trace_file.clone())),
+            no_a11y: true,
+            ..Default::default()
+        };
+        manager.run(&options).unwrap();
+
+        let run = &runner.calls_to("build")[0];
+        assert!(run.contains(&"--allow=devel".to_string()));
+        assert!(run.contains(&format!(
+            "--filesystem={}",
+            dir.path().join("traces").display()
+        )));
+        assert_eq!(
+            &run[run.len() - 5..],
+            strings(&[
+                "strace",
+                "-f",
+                "-o",
+                trace_file.to_str().unwrap(),
+                "example"
+            ])
+        );
+    }
+
+    #[test]
+    fn dump_env_leaves_the_clean_data_dir_alone() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let previous_run = dir.path().join(".flatplay/clean-data/data/previous-run");
+        fs::create_dir_all(previous_run.parent().unwrap()).unwrap();
+        fs::write(&previous_run, "").unwrap();
+        let options = RunOptions {
+            clean_data: true,
+            no_a11y: true,
+            ..Default::default()
+        };
+
+        manager
+            .run(&RunOptions {
+                dump_env: true,
+                ..options.clone()
+            })
+            .unwrap();
+        assert!(previous_run.exists());
+        assert!(runner.calls_to("build").is_empty());
+
+        manager.run(&options).unwrap();
+        assert!(!previous_run.exists());
+        assert!(dir.path().join(".flatplay/clean-data/data").is_dir());
+    }
+
+    #[test]
+    fn print_run_command_leaves_the_clean_data_dir_alone() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let previous_run = dir.path().join(".flatplay/clean-data/data/previous-run");
+        fs::create_dir_all(previous_run.parent().unwrap()).unwrap();
+        fs::write(&previous_run, "").unwrap();
+        let options = RunOptions {
+            clean_data: true,
+            no_a11y: true,
+            ..Default::default()
+        };
+
+        manager
+            .run(&RunOptions {
+                print_run_command: true,
+                ..options.clone()
+            })
+            .unwrap();
+        assert!(previous_run.exists());
+
+        // Nor does a run of an app that isn't built.
+        manager.run(&options).unwrap();
+        assert!(previous_run.exists());
+        assert!(runner.calls_to("build").is_empty());
+    }
+
+    #[test]
+    fn headless_runs_the_app_on_a_virtual_display() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        let options = RunOptions {
+            headless: true,
+            no_a11y: true,
+            ..Default::default()
+        };
+        manager.run(&options).unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+        let run = calls.last().unwrap();
+        assert_eq!(
+            &run[..4],
+            strings(&["xvfb-run", "--auto-servernum", flatpak_bin(), "build"])
+        );
+        assert!(run.contains(&"--nosocket=wayland".to_string()));
+        assert!(
+            !run.iter()
+                .any(|arg| arg.starts_with("--env=WAYLAND_DISPLAY="))
+        );
+        assert_eq!(run.last().unwrap(), "example");
+    }
+
+    #[test]
+    fn only_networked_modules_get_the_network() {
+        let networked = r#"{ "build-args": ["--share=network"] }"#;
+        let offline = r#"{ "build-args": [] }"#;
+        for (dependency_options, app_options, app_networked) in
+            [(networked, offline, false), (offline, networked, true)]
+        {
+            let (dir, config) = project_with_modules(&format!(
+                r#"{{
+                    "name": "dependency",
+                    "build-options": {dependency_options},
+                    "sources": [{{ "type": "archive", "url": "https://example.com/dep.tar.xz" }}]
+                }},
+                {{
+                    "name": "example",
+                    "buildsystem": "simple",
+                    "build-options": {app_options},
+                    "build-commands": ["cargo build"],
+                    "sources": [{{ "type": "dir", "path": "." }}]
+                }}"#
+            ));
+            let mut state = State::load(dir.path().to_path_buf()).unwrap();
+            state.dependencies_updated = true;
+            state.dependencies_built = true;
+            let runner = RecordingRunner::default();
+            let mut manager =
+                FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+            manager.build().unwrap();
+
+            let build = &runner.calls_to("build")[0];
+            assert_eq!(
+                build.contains(&"--share=network".to_string()),
+                app_networked
+            );
+        }
+    }
+
+    #[test]
+    fn sandbox_env_lets_the_env_file_win() {
+        let (dir, config) = project("meson");
+        let env_file = dir.path().join(".env");
+        fs::write(&env_file, "LANG=C.UTF-8\nAPP_MODE=devel\n").unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(RecordingRunner::default()))
+                .unwrap();
+        let options = RunOptions {
+            env_file: Some(env_file),
+            no_a11y: true,
+            x11: true,
+            ..Default::default()
+        };
+
+        let env = manager.sandbox_env(&options).unwrap();
+        assert_eq!(env["LANG"], "C.UTF-8");
+        assert_eq!(env["APP_MODE"], "devel");
+        assert_eq!(env["GDK_BACKEND"], "x11");
+    }
+
+    #[test]
+    fn app_module_build_options_are_merged() {
+        let (dir, config) = project_with_modules(
+            r#"{
+                "name": "example",
+                "buildsystem": "meson",
+                "build-options": {
+                    "cflags": "-O0",
+                    "env": { "RUST_LOG": "debug" },
+                    "config-opts": ["-Dtests=false"]
+                },
+                "sources": [{ "type": "dir", "path": "." }]
+            }"#,
+        );
+        let manifest_path = dir.path().join("com.example.App.json");
+        let mut manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        manifest["build-options"] =
+            serde_json::json!({ "cflags": "-g", "env": { "RUST_LOG": "info", "MODE": "dev" } });
+        fs::write(&manifest_path, manifest.to_string()).unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build().unwrap();
+
+        let setup = &runner.calls_to("build")[0];
+        for arg in [
+            "--env=CFLAGS=-g -O0",
+            "--env=MODE=dev",
+            "--env=RUST_LOG=debug",
+            "-Dtests=false",
+        ] {
+            assert!(
+                setup.contains(&arg.to_string()),
+                "{arg} missing from {setup:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn repo_only_export_skips_the_bundle() {
+        let (dir, config) = project("meson");
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.export_bundle(true, true, None).unwrap();
+
+        assert_eq!(runner.calls_to("build-finish").len(), 1);
+        assert_eq!(runner.calls_to("build-export").len(), 1);
+        assert!(runner.calls_to("build-bundle").is_empty());
+    }
+
+    #[test]
+    fn app_branch_flows_from_init_to_export_and_run() {
+        let (dir, mut config) = project("meson");
+        config.branch = Some("beta".to_string());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.export_bundle(true, false, None).unwrap();
+        manager.run_installed(&RunOptions::default()).unwrap();
+
+        // build-init only takes the runtime branch.
+        let init = &runner.calls_to("build-init")[0];
+        assert_eq!(init.last().unwrap(), "48");
+        assert!(!init.contains(&"beta".to_string()));
+        assert_eq!(runner.calls_to("build-export")[0].last().unwrap(), "beta");
+        assert_eq!(runner.calls_to("build-bundle")[0].last().unwrap(), "beta");
+        let run = &runner.calls_to("run")[0];
+        assert!(run.contains(&"--branch=beta".to_string()));
+        assert_eq!(run.last().unwrap(), "com.example.App");
+    }
+
+    #[test]
+    fn build_up_to_stops_after_the_module() {
+        let (dir, config) = project_with_modules(
+            r#"{ "name": "libfoo" }, { "name": "libbar" }, { "name": "example" }"#,
+        );
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build_up_to("libfoo").unwrap();
+        let err = manager.build_up_to("libqux").unwrap_err();
+        assert!(
+            err.to_string().starts_with("Module libqux not found"),
+            "{err}"
+        );
+        assert!(manager.build_up_to("example").is_err());
+
+        let builds = runner.calls_to("--force-clean");
+        assert_eq!(builds.len(), 1);
+        assert!(builds[0].contains(&"--stop-at=libbar".to_string()));
+        assert!(runner.calls_to("build").is_empty());
+        assert!(!state.dependencies_built);
+        assert!(!state.application_built);
+    }
+
+    #[test]
+    fn paths_resolve_from_the_manifest_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_dir = dir.path().join("packaging/flatpak");
+        fs::create_dir_all(manifest_dir.join("patches")).unwrap();
+        fs::write(manifest_dir.join("patches/libfoo.patch"), "").unwrap();
+        fs::write(
+            manifest_dir.join("libfoo.json"),
+            r#"{ "name": "libfoo", "sources": [{ "type": "patch", "path": "patches/libfoo.patch" }] }"#,
+        )
+        .unwrap();
+        fs::write(
+            manifest_dir.join("com.example.App.json"),
+            r#"{
+                "id": "com.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "48",
+                "command": "example",
+                "modules": [
+                    "libfoo.json",
+                    { "name": "example", "sources": [{ "type": "dir", "path": "../.." }] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        // Selected by absolute path, as when the manifest lives outside the repo.
+        state.active_manifest = Some(manifest_dir.join("com.example.App.json"));
+        let manager = FlatpakManager::with_runner(
+            &mut state,
+            Config::default(),
+            Box::new(RecordingRunner::default()),
+        )
+        .unwrap();
+
+        let modules = manager.modules().unwrap();
+        assert_eq!(modules[0].name(), "libfoo");
+        // The patch is relative to the module file, and changing it changes the module's hash.
+        let hashes = manager.dependency_hashes().unwrap();
+        fs::write(manifest_dir.join("patches/libfoo.patch"), "fix").unwrap();
+        assert_ne!(manager.dependency_hashes().unwrap(), hashes);
+        assert_eq!(
+            manager.app_source_dir().unwrap(),
+            manifest_dir.canonicalize().unwrap().join("../..")
+        );
+    }
+
+    #[test]
+    fn export_is_annotated_with_the_git_commit() {
+        let (dir, mut config) = project("meson");
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        config.git_commit = Some(commit.to_string());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.export_bundle(true, true, None).unwrap();
+
+        let export = &runner.calls_to("build-export")[0];
+        assert!(export.contains(&"--subject=com.example.App 0123456789ab".to_string()));
+        assert!(export.contains(&format!("--body=Built from git commit {commit}")));
+    }
+
+    #[test]
+    fn export_writes_a_flatpakref() {
+        let (dir, mut config) = project("meson");
+        config.branch = Some("devel".to_string());
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.application_built = true;
+        let runner = RecordingRunner::default();
+        let manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager
+            .export_bundle(true, true, Some("https://example.com/repo"))
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("com.example.App.flatpakref")).unwrap(),
+            "[Flatpak Ref]\nName=com.example.App\nBranch=devel\n\
+             Url=https://example.com/repo\nIsRuntime=false\n"
+        );
+    }
+
+    #[test]
+    fn post_build_hooks_run_after_the_build() {
+        let (dir, mut config) = project("meson");
+        config.post_build = vec!["cp -r .flatplay/repo /tmp/artifacts".to_string()];
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build().unwrap();
+
+        assert_eq!(
+            runner.calls.lock().unwrap().last().unwrap(),
+            &strings(&["sh", "-c", "cp -r .flatplay/repo /tmp/artifacts"])
+        );
+    }
+
+    #[test]
+    fn debug_build_runs_cmake_with_debug_build_type() {
+        let (dir, mut config) = project("cmake-ninja");
+        config.debug = true;
+        let mut state = State::load(dir.path().to_path_buf()).unwrap();
+        state.dependencies_updated = true;
+        state.dependencies_built = true;
+        let runner = RecordingRunner::default();
+        let mut manager =
+            FlatpakManager::with_runner(&mut state, config, Box::new(runner.clone())).unwrap();
+        manager.build().unwrap();
+
+        let configure = &runner.calls_to("build")[0];
+        assert_eq!(configure[3], "cmake");
+        assert!(configure.contains(&"-DCMAKE_BUILD_TYPE=Debug".to_string()));
+        assert!(configure.contains(&"-DCMAKE_INSTALL_PREFIX=/app".to_string()));
+        assert_eq!(
+            configure[configure.len() - 2..],
+            strings(&["-Dprofile=devel", "."])
+        );
+    }
+}
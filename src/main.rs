@@ -1,18 +1,118 @@
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use clap::{CommandFactory, Parser, Subcommand};
 use colored::*;
 use nix::unistd::{getpid, setpgid};
 
+use flatplay::FlatpakManager;
+use flatplay::check::check;
+use flatplay::completions::{Shell, generate, install as install_completions};
+use flatplay::config::{Config, DefaultCommand};
+use flatplay::error::{Error, exit_code};
+use flatplay::options::RunOptions;
 use flatplay::process::{is_process_running, kill_process_group};
+use flatplay::runtimes::prune_runtimes;
 use flatplay::state::State;
-use flatplay::FlatpakManager;
+use flatplay::status::{StatusFormat, print_status, status_json};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Target architecture, built into separate per-arch directories
+    #[arg(long, global = true)]
+    arch: Option<String>,
+
+    /// Flatpak installation to look for and install runtimes in: `user`, `system` or a name
+    #[arg(long, global = true, value_name = "INSTALLATION")]
+    installation: Option<String>,
+
+    /// Number of parallel build jobs
+    #[arg(long, short, global = true)]
+    jobs: Option<u32>,
+
+    /// Number of dependency modules marked `x-flatplay-independent` built at the same time
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_parallel_modules: Option<u32>,
+
+    /// Don't use ccache when building dependencies
+    #[arg(long, global = true)]
+    no_ccache: bool,
+
+    /// Share this ccache directory between builds instead of keeping one per build directory
+    #[arg(long, global = true, value_name = "PATH")]
+    ccache_dir: Option<PathBuf>,
+
+    /// Don't keep the build directories of dependencies
+    #[arg(long, global = true)]
+    no_keep_build_dirs: bool,
+
+    /// Extra flag passed verbatim to flatpak-builder, can be repeated
+    #[arg(
+        long = "builder-arg",
+        global = true,
+        value_name = "FLAG",
+        allow_hyphen_values = true
+    )]
+    builder_args: Vec<String>,
+
+    /// Kill builder and build system commands that run longer than this many seconds
+    #[arg(long, global = true, value_name = "SECS")]
+    command_timeout: Option<u64>,
+
+    /// Send a desktop notification when a build finishes
+    #[arg(long, global = true)]
+    notify: bool,
+
+    /// Install the runtime and SDK if they are missing
+    #[arg(long, global = true)]
+    install_deps: bool,
+
+    /// After a successful build, remove the intermediate build directories, keeping the repo
+    #[arg(long, global = true)]
+    prune: bool,
+
+    /// Log every command flatplay runs, with its exit status and duration, to trace.log in the build dir
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Note which flatpak-builder is used and how commands are spawned on the host
+    #[arg(long, short = 'v', global = true)]
+    verbose: bool,
+
+    /// Pass -v to flatpak and flatpak-builder, for their own logs such as OSTree details
+    #[arg(long, global = true)]
+    verbose_flatpak: bool,
+
+    /// Build the application even if the dependencies fail, then report every step that failed
+    #[arg(long, short = 'k', global = true)]
+    keep_going: bool,
+
+    /// Build the application with the debug build type of meson or cmake
+    #[arg(long, global = true)]
+    debug: bool,
+
+    /// Version to stamp into the app build and export, from `git describe` if no value is given
+    #[arg(long, global = true, value_name = "VERSION", num_args = 0..=1, require_equals = true)]
+    set_version: Option<Option<String>>,
+
+    /// Application branch to export to, defaulting to the manifest's `default-branch`
+    #[arg(long, global = true)]
+    branch: Option<String>,
+
+    /// Repo directory to build into and run from, instead of the one in the build directory
+    #[arg(long, global = true, value_name = "PATH")]
+    repo_dir: Option<PathBuf>,
+
+    /// Project root to work in, instead of the enclosing git repository
+    #[arg(long, global = true, value_name = "PATH")]
+    base_dir: Option<PathBuf>,
+
+    /// Answer prompts with their default choice instead of asking
+    #[arg(long, short = 'y', global = true, visible_alias = "assumeyes")]
+    yes: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -20,91 +120,257 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a Flatpak build, update the dependencies & build them
-    Build,
+    Build {
+        /// Only build the dependencies up to and including this module, skipping the application
+        #[arg(long, value_name = "MODULE")]
+        stop_at: Option<String>,
+    },
     /// Build or rebuild the application then run it
-    BuildAndRun,
+    BuildAndRun(RunOptions),
+    /// Clean the build directory and build everything again
+    Rebuild {
+        /// Run the application after rebuilding it
+        #[arg(long)]
+        run: bool,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
     /// Stop the currently running task
     Stop,
+    /// Show the active manifest, build progress and last build durations
+    Status {
+        /// Output format, `json` for a single object with the progress and whether flatplay is running
+        #[arg(long, value_enum, default_value_t)]
+        format: StatusFormat,
+    },
+    /// Validate the manifest without building it
+    Check {
+        /// Manifest to check instead of the active one
+        path: Option<PathBuf>,
+    },
     /// Run the application
-    Run,
+    Run {
+        /// Run the installed release of the application instead of the development build
+        #[arg(long, conflicts_with_all = ["profile", "wrapper", "strace"])]
+        installed: bool,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Open a shell in the sandbox the application runs in
+    Shell(RunOptions),
     /// Download/Update the dependencies and builds them
     UpdateDependencies,
+    /// Extract and patch a dependency module's sources and print where they are
+    Checkout {
+        /// Name of the module to check out
+        module: String,
+    },
     /// Clean the Flatpak repo directory
-    Clean,
+    Clean {
+        /// Also remove the configured repo directory when it is outside the build directory
+        #[arg(long)]
+        repo: bool,
+    },
+    /// Uninstall the runtimes and SDKs no installed app uses, after confirmation
+    PruneRuntimes,
     /// Spawn a new terminal inside the specified SDK
     RuntimeTerminal,
     /// Spawn a new terminal inside the current build repository
     BuildTerminal,
     /// Export .flatpak bundle from the build
-    ExportBundle,
+    ExportBundle {
+        /// Keep the finalized copy of the build repo instead of removing it after exporting
+        #[arg(long)]
+        keep_repo: bool,
+        /// Only export to the OSTree repo, e.g. to serve it, without building the bundle
+        #[arg(long)]
+        repo_only: bool,
+        /// Don't annotate the export with the current git commit
+        #[arg(long)]
+        no_git_metadata: bool,
+        /// Also write a .flatpakref installing the app from the OSTree repo served at this URL
+        #[arg(long, value_name = "URL")]
+        flatpakref_url: Option<String>,
+    },
     /// Select or change the active manifest
     SelectManifest {
-        /// Path to the manifest file to select
+        /// Path or http(s) URL of the manifest file to select
         path: Option<PathBuf>,
+        /// Select the Nth manifest (starting at 1) without prompting
+        #[arg(long, conflicts_with_all = ["path", "first"])]
+        index: Option<usize>,
+        /// Select the first manifest without prompting
+        #[arg(long, conflicts_with = "path")]
+        first: bool,
     },
     /// Generate shell completion scripts for your shell
     Completions {
-        /// The shell to generate completions for (e.g., bash, zsh, fish)
+        /// The shell to generate completions for (e.g., bash, zsh, fish, nushell)
         #[arg(value_enum)]
-        shell: clap_complete::Shell,
+        shell: Shell,
+        /// Write the completions to where the shell loads them from instead of printing them
+        #[arg(long)]
+        install: bool,
     },
 }
 
+// Prints a command error and evaluates to the exit code for it.
 macro_rules! handle_command {
     ($command:expr) => {
-        if let Err(err) = $command {
-            eprintln!("{}: {}", "Error".red(), err);
+        match $command {
+            Ok(_) => 0,
+            Err(err) => {
+                eprintln!("{}: {}", "Error".red(), err);
+                exit_code(&err)
+            }
         }
     };
 }
 
-fn get_base_dir() -> PathBuf {
+fn get_base_dir(base_dir: Option<&PathBuf>) -> Result<PathBuf, String> {
+    if let Some(base_dir) = base_dir {
+        if !base_dir.is_dir() {
+            return Err(format!("Base directory {base_dir:?} does not exist"));
+        }
+        return Ok(base_dir.clone());
+    }
+
     let output = Command::new("git")
         .arg("rev-parse")
         .arg("--show-toplevel")
         .output();
 
-    if let Ok(output) = output {
-        if output.status.success() {
-            return PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
-        }
+    if let Ok(output) = output
+        && output.status.success()
+    {
+        return Ok(PathBuf::from(
+            String::from_utf8_lossy(&output.stdout).trim(),
+        ));
+    }
+
+    // Outside a repository the build directory would land wherever we happen to be.
+    eprintln!(
+        "{} Not inside a git repository, using the current directory as the project root. Pass {} to use another one.",
+        "⚠".yellow(),
+        "--base-dir".bold()
+    );
+    Ok(PathBuf::from("."))
+}
+
+fn get_git_commit(base_dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(base_dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
     }
-    PathBuf::from(".")
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn get_git_version(base_dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["describe", "--tags", "--always", "--dirty"])
+        .current_dir(base_dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
 fn main() {
     let cli = Cli::parse();
 
     // Handle shell completions first.
-    if let Some(Commands::Completions { shell }) = cli.command {
-        use clap_complete::generate;
+    if let Some(Commands::Completions { shell, install }) = cli.command {
         use std::io;
         let mut cmd = Cli::command();
-        generate(shell, &mut cmd, "flatplay", &mut io::stdout());
+        if !install {
+            generate(shell, &mut cmd, "flatplay", &mut io::stdout());
+            return;
+        }
+        match install_completions(shell, &mut cmd, "flatplay") {
+            Ok(path) => {
+                println!("{} Installed completions to {:?}", "✔".green(), path);
+                if shell == Shell::Zsh {
+                    println!(
+                        "Add its directory to your fpath in ~/.zshrc: fpath=({:?} $fpath)",
+                        path.parent().unwrap()
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red(), e);
+                std::process::exit(1);
+            }
+        }
         return;
     }
 
-    let base_dir = get_base_dir();
+    let base_dir = match get_base_dir(cli.base_dir.as_ref()) {
+        Ok(base_dir) => base_dir,
+        Err(e) => {
+            eprintln!("{}: {}", "Error".red(), e);
+            std::process::exit(1);
+        }
+    };
     let base_dir_for_panic_hook = base_dir.clone();
-    let mut state = State::load(base_dir).unwrap();
+    let mut state = match State::load(base_dir) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}: Failed to load state: {}", "Error".red(), e);
+            std::process::exit(1);
+        }
+    };
 
     // Handle the "stop" command early.
     if let Some(Commands::Stop) = cli.command {
-        handle_command!(kill_process_group(&mut state));
+        std::process::exit(handle_command!(kill_process_group(&mut state)));
+    }
+
+    // Status only reads the state, so it works while another instance is running.
+    if let Some(Commands::Status { format }) = cli.command {
+        match format {
+            StatusFormat::Text => print_status(&state),
+            StatusFormat::Json => println!("{}", status_json(&state)),
+        }
         return;
     }
 
+    // These don't touch the build, so they work while another instance is running.
+    if let Some(command @ (Commands::Check { .. } | Commands::PruneRuntimes)) = &cli.command {
+        let code = match Config::load(&state.base_dir) {
+            Ok(mut config) => {
+                if cli.installation.is_some() {
+                    config.installation = cli.installation.clone();
+                }
+                config.non_interactive = cli.yes;
+                match command {
+                    Commands::Check { path } => {
+                        handle_command!(check(&state, &config, path.as_deref()))
+                    }
+                    _ => handle_command!(prune_runtimes(&config)),
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red(), e);
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
     // Check if another instance is already running.
-    if let Some(pgid) = state.process_group_id {
-        if is_process_running(pgid) {
-            eprintln!(
-                "{}: Another instance of flatplay is already running (PID: {}).",
-                "Error".red(),
-                pgid
-            );
-            eprintln!("Run '{}' to terminate it.", "flatplay stop".bold().italic());
-            return;
-        }
+    if let Some(pgid) = state.process_group_id
+        && is_process_running(pgid)
+    {
+        let err = Error::AlreadyRunning(pgid);
+        eprintln!("{}: {}", "Error".red(), err);
+        eprintln!("Run '{}' to terminate it.", "flatplay stop".bold().italic());
+        std::process::exit(err.exit_code());
     }
 
     // Become a process group leader.
@@ -134,36 +400,161 @@ fn main() {
         original_hook(panic_info);
     }));
 
-    let mut flatpak_manager = match FlatpakManager::new(&mut state) {
-        Ok(manager) => manager,
+    let mut config = match Config::load(&state.base_dir) {
+        Ok(config) => config,
         Err(e) => {
             eprintln!("{}: {}", "Error".red(), e);
             std::process::exit(1);
         }
     };
+    // CLI flags take precedence over the config file.
+    if cli.arch.is_some() {
+        config.arch = cli.arch.clone();
+    }
+    if cli.installation.is_some() {
+        config.installation = cli.installation.clone();
+    }
+    if let Some(repo_dir) = &cli.repo_dir {
+        // Unlike the config file, which is relative to the project, the flag is relative to the cwd.
+        config.repo_dir = Some(std::path::absolute(repo_dir).unwrap_or_else(|_| repo_dir.clone()));
+    }
+    if cli.jobs.is_some() {
+        config.jobs = cli.jobs;
+    }
+    if cli.max_parallel_modules.is_some() {
+        config.max_parallel_modules = cli.max_parallel_modules;
+    }
+    if cli.no_ccache {
+        config.ccache = false;
+    }
+    if let Some(ccache_dir) = &cli.ccache_dir {
+        config.ccache_dir =
+            Some(std::path::absolute(ccache_dir).unwrap_or_else(|_| ccache_dir.clone()));
+    }
+    if cli.no_keep_build_dirs {
+        config.keep_build_dirs = false;
+    }
+    config.builder_args.extend(cli.builder_args.iter().cloned());
+    if cli.command_timeout.is_some() {
+        config.command_timeout = cli.command_timeout;
+    }
+    if cli.notify {
+        config.notify = true;
+    }
+    if cli.install_deps {
+        config.install_deps = true;
+    }
+    config.non_interactive = cli.yes;
+    config.prune = cli.prune;
+    config.debug = cli.debug;
+    config.trace = cli.trace;
+    config.verbose = cli.verbose;
+    config.verbose_flatpak = cli.verbose_flatpak;
+    config.keep_going = cli.keep_going;
+    config.branch = cli.branch.clone();
+    config.version = match &cli.set_version {
+        Some(Some(version)) => Some(version.clone()),
+        Some(None) => match get_git_version(&state.base_dir) {
+            Some(version) => Some(version),
+            None => {
+                eprintln!(
+                    "{}: Couldn't derive a version with `git describe`. Pass one to --set-version.",
+                    "Error".red()
+                );
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if let Some(Commands::ExportBundle {
+        no_git_metadata: false,
+        ..
+    }) = &cli.command
+    {
+        config.git_commit = get_git_commit(&state.base_dir);
+    }
+
+    let default_command = config.default_command;
+    let mut flatpak_manager = match FlatpakManager::new(&mut state, config) {
+        Ok(manager) => manager,
+        Err(e) => {
+            eprintln!("{}: {}", "Error".red(), e);
+            std::process::exit(exit_code(&e));
+        }
+    };
 
-    match &cli.command {
+    let code = match &cli.command {
         // Handled earlier.
-        Some(Commands::Completions { shell: _ }) => {}
-        Some(Commands::Stop) => {}
+        Some(Commands::Completions { .. }) => 0,
+        Some(Commands::Stop) => 0,
+        Some(Commands::Status { .. } | Commands::Check { .. } | Commands::PruneRuntimes) => 0,
 
-        Some(Commands::Build) => handle_command!(flatpak_manager.build()),
-        Some(Commands::BuildAndRun) => handle_command!(flatpak_manager.build_and_run()),
-        Some(Commands::Run) => handle_command!(flatpak_manager.run()),
+        Some(Commands::Build { stop_at: None }) => handle_command!(flatpak_manager.build()),
+        Some(Commands::Build {
+            stop_at: Some(module),
+        }) => handle_command!(flatpak_manager.build_up_to(module)),
+        Some(Commands::BuildAndRun(options)) => {
+            handle_command!(flatpak_manager.build_and_run(options))
+        }
+        Some(Commands::Rebuild { run, run_options }) => {
+            if *run {
+                handle_command!(flatpak_manager.rebuild_and_run(run_options))
+            } else {
+                handle_command!(flatpak_manager.rebuild())
+            }
+        }
+        Some(Commands::Run {
+            installed,
+            run_options,
+        }) => {
+            if *installed {
+                handle_command!(flatpak_manager.run_installed(run_options))
+            } else {
+                handle_command!(flatpak_manager.run(run_options))
+            }
+        }
+        Some(Commands::Shell(options)) => handle_command!(flatpak_manager.shell(options)),
         Some(Commands::UpdateDependencies) => {
             handle_command!(flatpak_manager.update_dependencies())
         }
-        Some(Commands::Clean) => handle_command!(flatpak_manager.clean()),
+        Some(Commands::Checkout { module }) => handle_command!(flatpak_manager.checkout(module)),
+        Some(Commands::Clean { repo }) => handle_command!(flatpak_manager.clean(*repo)),
         Some(Commands::RuntimeTerminal) => handle_command!(flatpak_manager.runtime_terminal()),
         Some(Commands::BuildTerminal) => handle_command!(flatpak_manager.build_terminal()),
-        Some(Commands::ExportBundle) => handle_command!(flatpak_manager.export_bundle()),
-        Some(Commands::SelectManifest { path }) => {
-            handle_command!(flatpak_manager.select_manifest(path.clone()))
+        Some(Commands::ExportBundle {
+            keep_repo,
+            repo_only,
+            flatpakref_url,
+            ..
+        }) => handle_command!(flatpak_manager.export_bundle(
+            *keep_repo,
+            *repo_only,
+            flatpakref_url.as_deref()
+        )),
+        Some(Commands::SelectManifest { path, index, first }) => {
+            let index = if *first { Some(1) } else { *index };
+            handle_command!(flatpak_manager.select_manifest(path.clone(), index))
         }
-        None => handle_command!(flatpak_manager.build_and_run()),
-    }
+        None => match default_command {
+            DefaultCommand::BuildAndRun => {
+                handle_command!(flatpak_manager.build_and_run(&RunOptions::default()))
+            }
+            DefaultCommand::Build => handle_command!(flatpak_manager.build()),
+            DefaultCommand::Run => handle_command!(flatpak_manager.run(&RunOptions::default())),
+            DefaultCommand::Rebuild => handle_command!(flatpak_manager.rebuild()),
+            DefaultCommand::UpdateDependencies => {
+                handle_command!(flatpak_manager.update_dependencies())
+            }
+        },
+    };
 
     // Clean up pgid in the state file on normal exit.
     state.process_group_id = None;
-    state.save().unwrap();
+    if let Err(e) = state.save() {
+        eprintln!("{}: Failed to save state: {}", "Error".red(), e);
+        std::process::exit(if code != 0 { code } else { 1 });
+    }
+    if code != 0 {
+        std::process::exit(code);
+    }
 }
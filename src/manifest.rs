@@ -1,30 +1,336 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 
-fn is_valid_dbus_name(name: &str) -> bool {
+use crate::cleanup::glob_match;
+
+/// Validates an application ID following flatpak's `flatpak_is_valid_name` rules:
+/// at most 255 characters, at least three non-empty elements separated by periods,
+/// elements made of `[A-Za-z0-9_-]` that don't start with a digit, and `-` only in the last element.
+fn is_valid_app_id(name: &str) -> bool {
     if name.is_empty() || name.len() > 255 {
         return false;
     }
     let elements: Vec<&str> = name.split('.').collect();
-    if elements.len() < 2 {
+    if elements.len() < 3 {
         return false;
     }
-    elements.iter().all(|element| {
-        if let Some(first_char) = element.chars().next() {
-            !element.is_empty()
-                && !first_char.is_ascii_digit()
-                && element
-                    .chars()
-                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
-        } else {
-            false
+    let last_index = elements.len() - 1;
+    elements.iter().enumerate().all(|(index, element)| {
+        let allow_dash = index == last_index;
+        let is_valid_char =
+            |c: char| c.is_ascii_alphanumeric() || c == '_' || (allow_dash && c == '-');
+        match element.chars().next() {
+            Some(first_char) => !first_char.is_ascii_digit() && element.chars().all(is_valid_char),
+            None => false,
+        }
+    })
+}
+
+/// Removes `//` and `/* */` comments from JSON content, leaving string literals untouched.
+/// flatpak-builder accepts commented JSON manifests, but serde_json does not.
+fn strip_json_comments(content: &str) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            output.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    output.push(escaped);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                output.push(c);
+            }
+            ('/', Some('/')) => {
+                // Keep the newline so line numbers in parse errors stay accurate.
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        output.push(c);
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        output.push(c);
+                    }
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// Parses the first non-empty document of a YAML file, resolving `<<` merge keys.
+/// Tools sometimes emit `---` markers or trailing documents, which `serde_yaml::from_str` rejects.
+/// Also returns whether the document had merge keys, which flatpak-builder doesn't resolve.
+fn parse_first_yaml_value(content: &str) -> Result<(serde_yaml::Value, bool)> {
+    for document in serde_yaml::Deserializer::from_str(content) {
+        let value = serde_yaml::Value::deserialize(document)?;
+        if !value.is_null() {
+            let mut merged = value.clone();
+            merged.apply_merge()?;
+            let has_merge_keys = merged != value;
+            return Ok((merged, has_merge_keys));
+        }
+    }
+    Err(anyhow::anyhow!("Empty YAML document"))
+}
+
+/// Parses a JSON or YAML file, picking the format from its extension. Also returns whether it
+/// was YAML with `<<` merge keys.
+fn parse_manifest_file_with_merge_keys<T: DeserializeOwned>(path: &Path) -> Result<(T, bool)> {
+    let content = fs::read_to_string(path)?;
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("json") => Ok((serde_json::from_str(&strip_json_comments(&content))?, false)),
+        Some("yaml") | Some("yml") => {
+            let (value, has_merge_keys) = parse_first_yaml_value(&content)?;
+            Ok((serde_yaml::from_value(value)?, has_merge_keys))
+        }
+        _ => Err(anyhow::anyhow!("Unsupported manifest format")),
+    }
+}
+
+/// Parses a JSON or YAML file, picking the format from its extension.
+fn parse_manifest_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    Ok(parse_manifest_file_with_merge_keys(path)?.0)
+}
+
+fn has_manifest_extension(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|s| s.to_str()),
+        Some("json") | Some("yaml") | Some("yml")
+    )
+}
+
+/// Returns true if `path` is an http(s) URL rather than a local path.
+pub fn is_manifest_url(path: &str) -> bool {
+    path.starts_with("https://") || path.starts_with("http://")
+}
+
+/// Returns the manifest format extension of a remote manifest URL, ignoring any query string.
+pub fn remote_manifest_extension(url: &str) -> Option<&'static str> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let extension = path.rsplit('/').next()?.rsplit_once('.')?.1;
+    match extension {
+        "json" => Some("json"),
+        "yaml" => Some("yaml"),
+        "yml" => Some("yml"),
+        _ => None,
+    }
+}
+
+/// Returns true if a module reference includes several files: a directory ending in `/`,
+/// or a glob in the file name.
+fn is_module_include(reference: &str) -> bool {
+    reference.ends_with('/') || reference.contains(['*', '?'])
+}
+
+/// Expands a module reference into the module files it points to, relative to `manifest_dir`.
+/// Directory and glob includes expand to the matching JSON/YAML files sorted by name, and must
+/// match at least one. Plain references are returned as is.
+pub(crate) fn expand_module_reference(reference: &str, manifest_dir: &Path) -> Result<Vec<String>> {
+    if !is_module_include(reference) {
+        return Ok(vec![reference.to_string()]);
+    }
+    let (dir, pattern) = match reference.strip_suffix('/') {
+        Some(dir) => (dir, "*"),
+        None => reference.rsplit_once('/').unwrap_or(("", reference)),
+    };
+    if dir.contains(['*', '?']) {
+        return Err(anyhow::anyhow!(
+            "Module include {:?}: globs are only supported in the file name",
+            reference
+        ));
+    }
+
+    let entries = fs::read_dir(manifest_dir.join(dir))
+        .map_err(|e| anyhow::anyhow!("Module include {:?}: {}", reference, e))?;
+    let mut files: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file() && has_manifest_extension(&entry.path()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| glob_match(pattern, name))
+        .map(|name| {
+            if dir.is_empty() {
+                name
+            } else {
+                format!("{dir}/{name}")
+            }
+        })
+        .collect();
+    if files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Module include {:?} matched no module files",
+            reference
+        ));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Expands the module includes in the `modules` of a raw manifest or module, recursively.
+/// Returns true if anything was expanded.
+fn expand_module_includes(value: &mut serde_json::Value, manifest_dir: &Path) -> Result<bool> {
+    let Some(modules) = value
+        .get_mut("modules")
+        .and_then(serde_json::Value::as_array_mut)
+    else {
+        return Ok(false);
+    };
+    let mut expanded_any = false;
+    let mut expanded = Vec::with_capacity(modules.len());
+    for mut module in modules.drain(..) {
+        match module.as_str() {
+            Some(reference) if is_module_include(reference) => {
+                let files = expand_module_reference(reference, manifest_dir)?;
+                expanded.extend(files.into_iter().map(serde_json::Value::String));
+                expanded_any = true;
+            }
+            _ => {
+                expanded_any |= expand_module_includes(&mut module, manifest_dir)?;
+                expanded.push(module);
+            }
+        }
+    }
+    *modules = expanded;
+    Ok(expanded_any)
+}
+
+/// Loads the modules a reference points to, with their local source paths made relative to
+/// `manifest_dir` like those of inline modules.
+pub(crate) fn load_module_reference(reference: &str, manifest_dir: &Path) -> Result<Vec<Module>> {
+    let mut modules = Vec::new();
+    for file in expand_module_reference(reference, manifest_dir)? {
+        let mut module: Module = parse_manifest_file(&manifest_dir.join(&file))
+            .map_err(|e| anyhow::anyhow!("Failed to load module {:?}: {}", file, e))?;
+        module.rebase_source_paths(Path::new(&file).parent().unwrap_or(Path::new("")));
+        modules.push(module);
+    }
+    Ok(modules)
+}
+
+/// The manifest handed to flatpak-builder. When it is a temporary copy, it is removed on drop.
+pub struct BuilderManifest {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl BuilderManifest {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for BuilderManifest {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Returns the manifest to pass to flatpak-builder, which only understands single-file module
+/// references, list `finish-args` and YAML without merge keys. If the manifest uses directory or
+/// glob includes, mapping `finish-args` or merge keys, a copy with them expanded is written next
+/// to it, so relative paths keep working, and removed once the result is dropped.
+pub fn builder_manifest(path: &Path) -> Result<BuilderManifest> {
+    filtered_builder_manifest(path, None)
+}
+
+/// Top-level modules kept in a filtered builder manifest, by name.
+#[derive(Debug, Clone, Copy)]
+pub enum ModuleFilter<'a> {
+    Except(&'a [String]),
+    Only(&'a str),
+}
+
+/// Returns the name of a top-level module of the manifest in `manifest_dir`, reading it from
+/// the referenced file for single-file references.
+fn module_value_name(module: &serde_json::Value, manifest_dir: &Path) -> Option<String> {
+    match module {
+        serde_json::Value::String(reference) => {
+            let module: serde_json::Value =
+                parse_manifest_file(&manifest_dir.join(reference)).ok()?;
+            module["name"].as_str().map(str::to_string)
         }
+        module => module["name"].as_str().map(str::to_string),
+    }
+}
+
+/// Returns the manifest to pass to flatpak-builder like `builder_manifest`, keeping only the
+/// top-level modules `filter` selects. Filtered copies are named after the filter, so several
+/// of them can be built at once.
+pub fn filtered_builder_manifest(
+    path: &Path,
+    filter: Option<ModuleFilter>,
+) -> Result<BuilderManifest> {
+    let manifest_dir = path.parent().unwrap_or(Path::new("."));
+    let (mut value, has_merge_keys): (serde_json::Value, bool) =
+        parse_manifest_file_with_merge_keys(path)?;
+    let normalized = normalize_finish_args(&mut value)?;
+    let expanded = expand_module_includes(&mut value, manifest_dir)? | has_merge_keys;
+    if let (Some(filter), Some(modules)) = (filter, value["modules"].as_array_mut()) {
+        modules.retain(|module| {
+            let name = module_value_name(module, manifest_dir);
+            match filter {
+                ModuleFilter::Except(names) => !name.is_some_and(|name| names.contains(&name)),
+                ModuleFilter::Only(only) => name.as_deref() == Some(only),
+            }
+        });
+    }
+    if !expanded && !normalized && filter.is_none() {
+        return Ok(BuilderManifest {
+            path: path.to_path_buf(),
+            temporary: false,
+        });
+    }
+
+    let file_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("manifest");
+    let expanded_path = match filter {
+        Some(ModuleFilter::Only(name)) => {
+            manifest_dir.join(format!(".{file_name}.{name}.flatplay.json"))
+        }
+        _ => manifest_dir.join(format!(".{file_name}.flatplay.json")),
+    };
+    fs::write(&expanded_path, serde_json::to_string_pretty(&value)?)?;
+    Ok(BuilderManifest {
+        path: expanded_path,
+        temporary: true,
     })
 }
 
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Module {
@@ -38,60 +344,585 @@ pub enum Module {
         build_commands: Option<Vec<String>>,
         #[serde(rename = "post-install", default)]
         post_install: Option<Vec<String>>,
+        // Skipped when absent, so the input hashes of modules without it stay the same.
+        #[serde(
+            rename = "build-options",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        build_options: Option<Box<BuildOptions>>,
+        /// Whether the module neither needs nor is needed by the other modules, so it can be
+        /// built on its own. Skipped when unset, like `build_options`.
+        #[serde(
+            rename = "x-flatplay-independent",
+            default,
+            skip_serializing_if = "is_false"
+        )]
+        independent: bool,
         #[serde(default)]
         sources: Vec<serde_json::Value>,
     },
     Reference(String),
 }
 
+/// Build systems flatplay knows how to drive for the application module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildSystem {
+    Meson,
+    CMake,
+    Simple,
+    Autotools,
+}
+
+impl BuildSystem {
+    /// Maps a manifest `buildsystem` value to a build system.
+    /// Like flatpak-builder, a missing or unrecognized value falls back to autotools.
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("meson") => BuildSystem::Meson,
+            Some("cmake") | Some("cmake-ninja") => BuildSystem::CMake,
+            Some("simple") => BuildSystem::Simple,
+            _ => BuildSystem::Autotools,
+        }
+    }
+}
+
+impl Module {
+    /// Returns the module name, or the referenced file for unresolved references.
+    pub fn name(&self) -> &str {
+        match self {
+            Module::Object { name, .. } => name,
+            Module::Reference(path) => path,
+        }
+    }
+
+    /// Returns true if the module is marked `x-flatplay-independent`.
+    pub fn is_independent(&self) -> bool {
+        matches!(
+            self,
+            Module::Object {
+                independent: true,
+                ..
+            }
+        )
+    }
+
+    /// Makes relative local source paths relative to the directory containing `dir` instead,
+    /// for modules loaded from a file in `dir`.
+    fn rebase_source_paths(&mut self, dir: &Path) {
+        let Module::Object { sources, .. } = self else {
+            return;
+        };
+        let rebase = |path: &mut serde_json::Value| {
+            if let Some(p) = path.as_str()
+                && !Path::new(p).is_absolute()
+            {
+                *path = serde_json::Value::String(dir.join(p).to_string_lossy().into_owned());
+            }
+        };
+        for source in sources {
+            if let Some(path) = source.get_mut("path") {
+                rebase(path);
+            }
+            if let Some(paths) = source.get_mut("paths").and_then(|p| p.as_array_mut()) {
+                paths.iter_mut().for_each(rebase);
+            }
+        }
+    }
+
+    /// Returns the build system of the module, or `None` for unresolved references.
+    pub fn buildsystem(&self) -> Option<BuildSystem> {
+        match self {
+            Module::Object { buildsystem, .. } => {
+                Some(BuildSystem::from_name(buildsystem.as_deref()))
+            }
+            Module::Reference(_) => None,
+        }
+    }
+}
+
+/// Compiler flags and environment applied to the build, as in a manifest `build-options`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct BuildOptions {
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
+    pub cppflags: Option<String>,
+    pub ldflags: Option<String>,
+    #[serde(deserialize_with = "deserialize_env")]
+    pub env: BTreeMap<String, String>,
+    #[serde(rename = "build-args")]
+    pub build_args: Vec<String>,
+    #[serde(rename = "config-opts")]
+    pub config_opts: Vec<String>,
+    /// Per-architecture overrides, keyed by flatpak architecture name.
+    pub arch: BTreeMap<String, BuildOptions>,
+}
+
+/// Reads `build-options` env values, taking numbers and booleans as strings, like `JOBS: 4`
+/// in YAML.
+fn deserialize_env<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => Ok((key, value)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                Ok((key, value.to_string()))
+            }
+            _ => Err(serde::de::Error::custom(format!(
+                "env value of {key} must be a string, number or boolean"
+            ))),
+        })
+        .collect()
+}
+
+impl BuildOptions {
+    /// Merges `other` over these options. Like flatpak-builder, compiler flags are appended,
+    /// `env` entries from `other` win, and argument lists are concatenated.
+    pub fn merge(&mut self, other: &BuildOptions) {
+        fn append_flags(base: &mut Option<String>, extra: &Option<String>) {
+            if let Some(extra) = extra {
+                *base = Some(match base.take() {
+                    Some(flags) if !flags.is_empty() => format!("{flags} {extra}"),
+                    _ => extra.clone(),
+                });
+            }
+        }
+        append_flags(&mut self.cflags, &other.cflags);
+        append_flags(&mut self.cxxflags, &other.cxxflags);
+        append_flags(&mut self.cppflags, &other.cppflags);
+        append_flags(&mut self.ldflags, &other.ldflags);
+        self.env
+            .extend(other.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.build_args.extend(other.build_args.iter().cloned());
+        self.config_opts.extend(other.config_opts.iter().cloned());
+    }
+
+    /// Returns the options with the block matching `arch`, if any, merged over the base ones.
+    pub fn for_arch(&self, arch: &str) -> BuildOptions {
+        let mut options = BuildOptions {
+            arch: BTreeMap::new(),
+            ..self.clone()
+        };
+        if let Some(arch_options) = self.arch.get(arch) {
+            options.merge(arch_options);
+        }
+        options
+    }
+
+    /// Returns the `flatpak build` arguments setting the compiler flags and environment.
+    pub fn env_args(&self) -> Vec<String> {
+        let flags = [
+            ("CFLAGS", &self.cflags),
+            ("CXXFLAGS", &self.cxxflags),
+            ("CPPFLAGS", &self.cppflags),
+            ("LDFLAGS", &self.ldflags),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| (name, value)))
+            .chain(self.env.iter().map(|(k, v)| (k.as_str(), v)))
+            .map(|(name, value)| format!("--env={name}={value}"))
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
     #[serde(alias = "app-id")]
     pub id: String,
     pub sdk: String,
     pub runtime: String,
-    #[serde(rename = "runtime-version")]
+    /// Branch of the runtime and SDK. May instead be given inline, as in `runtime: id//branch`.
+    #[serde(rename = "runtime-version", default)]
     pub runtime_version: String,
-    pub command: String,
+    /// Optional, as runtimes and extensions built from a manifest have nothing to launch.
+    pub command: Option<String>,
+    #[serde(rename = "sdk-extensions", default)]
+    pub sdk_extensions: Vec<String>,
     #[serde(rename = "x-run-args")]
     pub x_run_args: Option<Vec<String>>,
     #[serde(default)]
     pub modules: Vec<Module>,
-    #[serde(rename = "finish-args", default)]
+    #[serde(
+        rename = "finish-args",
+        default,
+        deserialize_with = "deserialize_finish_args"
+    )]
     pub finish_args: Vec<String>,
     #[serde(rename = "build-options", default)]
-    pub build_options: serde_json::Value,
+    pub build_options: BuildOptions,
     #[serde(default)]
     pub cleanup: Vec<String>,
+    #[serde(rename = "rename-desktop-file")]
+    pub rename_desktop_file: Option<String>,
+    #[serde(rename = "rename-icon")]
+    pub rename_icon: Option<String>,
+    pub base: Option<String>,
+    #[serde(rename = "base-version")]
+    pub base_version: Option<String>,
+    #[serde(rename = "default-branch")]
+    pub default_branch: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(rename = "add-extensions", default)]
+    pub add_extensions: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    /// Whether the manifest builds a runtime, installed to `/usr`, rather than an app.
+    #[serde(rename = "build-runtime", default)]
+    pub build_runtime: bool,
+    /// Whether flatpak-builder moves translations to a separate `.Locale` extension.
+    #[serde(rename = "separate-locales", default = "default_true")]
+    pub separate_locales: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `finish-args` as written in a manifest: the usual list of flags, or a mapping from flag names
+/// to a value, a list of values, or `true` for flags without one, like `share: [network, ipc]`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FinishArgs {
+    List(Vec<String>),
+    Map(BTreeMap<String, FinishArgValues>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FinishArgValues {
+    Flag(bool),
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FinishArgs {
+    /// Returns the flags as a list, expanding a mapping to one `--name=value` flag per value.
+    fn into_args(self) -> Vec<String> {
+        let map = match self {
+            FinishArgs::List(args) => return args,
+            FinishArgs::Map(map) => map,
+        };
+        map.into_iter()
+            .flat_map(|(name, values)| {
+                let name = name.trim_start_matches('-').to_string();
+                let values = match values {
+                    FinishArgValues::Flag(true) => return vec![format!("--{name}")],
+                    FinishArgValues::Flag(false) => return Vec::new(),
+                    FinishArgValues::One(value) => vec![value],
+                    FinishArgValues::Many(values) => values,
+                };
+                values
+                    .into_iter()
+                    .map(|value| format!("--{name}={value}"))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn deserialize_finish_args<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    FinishArgs::deserialize(deserializer).map(FinishArgs::into_args)
+}
+
+/// Rewrites mapping `finish-args` as the list flatpak-builder expects. Returns whether it did.
+fn normalize_finish_args(manifest: &mut serde_json::Value) -> Result<bool> {
+    let Some(finish_args) = manifest
+        .get_mut("finish-args")
+        .filter(|args| args.is_object())
+    else {
+        return Ok(false);
+    };
+    let args = serde_json::from_value::<FinishArgs>(finish_args.take())?.into_args();
+    *finish_args = args.into();
+    Ok(true)
+}
+
+/// Replaces the file arguments flatpak marks with `@@`/`@@u` in exported commands, like
+/// `@@u %U @@`, by `extra_args`, as launching the app with files would. Only the first marked
+/// group gets them, and the others are dropped. Without markers, `extra_args` are appended.
+fn expand_file_placeholders(run_args: &[String], extra_args: &[String]) -> Vec<String> {
+    let mut args = Vec::with_capacity(run_args.len() + extra_args.len());
+    let mut extra_args = Some(extra_args);
+    let mut in_placeholder = false;
+    for arg in run_args {
+        match arg.as_str() {
+            "@@" | "@@u" if !in_placeholder => {
+                in_placeholder = true;
+                args.extend(extra_args.take().into_iter().flatten().cloned());
+            }
+            "@@" => in_placeholder = false,
+            _ if in_placeholder => {}
+            _ => args.push(arg.clone()),
+        }
+    }
+    args.extend(extra_args.into_iter().flatten().cloned());
+    args
 }
 
 impl Manifest {
+    /// Returns the command line used to launch the application: the manifest `command`,
+    /// followed by `x-run-args`, followed by `extra_args` so callers can override the manifest.
+    pub fn run_command_args(&self, extra_args: &[String]) -> Result<Vec<String>> {
+        let command = self
+            .command
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The manifest declares no command to run"))?;
+        let mut args = vec![command];
+        args.extend(self.run_args(extra_args));
+        Ok(args)
+    }
+
+    /// Returns the `x-run-args` followed by `extra_args`, or with `extra_args` in place of the
+    /// `@@`/`@@u` file placeholders if the `x-run-args` have any.
+    pub fn run_args(&self, extra_args: &[String]) -> Vec<String> {
+        let x_run_args = self.x_run_args.as_deref().unwrap_or_default();
+        expand_file_placeholders(x_run_args, extra_args)
+    }
+
+    /// Returns the extra `build-init` options derived from the manifest, such as the base app
+    /// and the SDK extensions.
+    pub fn build_init_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .sdk_extensions
+            .iter()
+            .map(|extension| format!("--sdk-extension={extension}"))
+            .collect();
+        // Like flatpak-builder, runtimes are built on top of a writable copy of the SDK.
+        if self.build_runtime {
+            args.extend(["--type=runtime".to_string(), "--writable-sdk".to_string()]);
+        }
+        if let Some(base) = &self.base {
+            args.push(format!("--base={base}"));
+            if let Some(base_version) = &self.base_version {
+                args.push(format!("--base-version={base_version}"));
+            }
+        }
+        args
+    }
+
+    /// Returns the prefix modules are installed to: `/usr` for runtimes, `/app` for apps.
+    pub fn install_prefix(&self) -> &'static str {
+        if self.build_runtime { "/usr" } else { "/app" }
+    }
+
+    /// Returns the `bin` directories of the SDK extensions, as mounted under `/usr/lib/sdk`.
+    pub fn sdk_extension_bin_dirs(&self) -> Vec<String> {
+        self.sdk_extensions
+            .iter()
+            .map(|extension| {
+                let name = extension.rsplit('.').next().unwrap_or(extension);
+                format!("/usr/lib/sdk/{name}/bin")
+            })
+            .collect()
+    }
+
+    /// Returns the `build-finish` arguments declaring the `add-extensions` extension points.
+    pub fn extension_args(&self) -> Vec<String> {
+        self.extension_properties()
+            .map(|(name, key, value)| format!("--extension={name}={key}={value}"))
+            .collect()
+    }
+
+    /// Returns the `add-extensions` extension points as metadata key file groups, so `flatpak
+    /// build` mounts the extensions before the app is finished.
+    pub fn extension_metadata(&self) -> String {
+        let mut metadata = String::new();
+        let mut current = None;
+        for (name, key, value) in self.extension_properties() {
+            if current != Some(name) {
+                metadata.push_str(&format!("\n[Extension {name}]\n"));
+                current = Some(name);
+            }
+            metadata.push_str(&format!("{key}={value}\n"));
+        }
+        metadata
+    }
+
+    fn extension_properties(&self) -> impl Iterator<Item = (&str, &str, String)> {
+        self.add_extensions.iter().flat_map(|(name, properties)| {
+            properties.iter().map(move |(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (name.as_str(), key.as_str(), value)
+            })
+        })
+    }
+
+    /// Returns the `build-finish` arguments writing the manifest `tags` to the app metadata,
+    /// formatted as a key file string list like flatpak-builder does.
+    pub fn metadata_args(&self) -> Vec<String> {
+        if self.tags.is_empty() {
+            return Vec::new();
+        }
+        vec![format!(
+            "--metadata=Application=tags={};",
+            self.tags.join(";")
+        )]
+    }
+
+    /// Returns the `build-finish` arguments for the `extra-data` sources of all modules,
+    /// including those in module files referenced from `manifest_dir`.
+    pub fn extra_data_args(&self, manifest_dir: &Path) -> Result<Vec<String>> {
+        Ok(self
+            .resolved_modules(manifest_dir)?
+            .iter()
+            .filter_map(|module| match module {
+                Module::Object { sources, .. } => Some(sources),
+                Module::Reference(_) => None,
+            })
+            .flatten()
+            .filter(|source| source["type"] == "extra-data")
+            .filter_map(|source| {
+                Some(format!(
+                    "--extra-data={}:{}:{}:{}:{}",
+                    source["filename"].as_str()?,
+                    source["sha256"].as_str()?,
+                    source["size"].as_u64()?,
+                    source["installed-size"].as_u64().unwrap_or(0),
+                    source["url"].as_str()?,
+                ))
+            })
+            .collect())
+    }
+
+    /// Returns the modules with references to module files, including directory and glob
+    /// includes, loaded from `manifest_dir`. Local source paths of loaded modules are rebased
+    /// onto `manifest_dir`.
+    pub fn resolved_modules(&self, manifest_dir: &Path) -> Result<Vec<Module>> {
+        let mut modules = Vec::with_capacity(self.modules.len());
+        for module in &self.modules {
+            let Module::Reference(reference) = module else {
+                modules.push(module.clone());
+                continue;
+            };
+            modules.extend(load_module_reference(reference, manifest_dir)?);
+        }
+        Ok(modules)
+    }
+
+    /// Moves a branch given inline in `runtime` or `sdk` to `runtime_version`, so they only
+    /// hold IDs. An explicit `runtime-version` wins over the inline branches.
+    fn normalize_refs(&mut self) -> Result<()> {
+        let (runtime, runtime_branch) = split_ref(&self.runtime);
+        let (sdk, sdk_branch) = split_ref(&self.sdk);
+        self.runtime = runtime;
+        self.sdk = sdk;
+        if self.runtime_version.is_empty() {
+            self.runtime_version = runtime_branch.or(sdk_branch).ok_or_else(|| {
+                anyhow::anyhow!("Missing runtime-version, and no branch in the runtime or SDK")
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Parses a manifest like `from_file`, but returns the problems `from_file` would fail on,
+    /// along with missing local sources, instead of stopping at the first one.
+    pub fn check_file(path: &Path) -> Result<(Self, Vec<String>)> {
+        let mut manifest: Manifest = parse_manifest_file(path)?;
+        let mut problems = Vec::new();
+        if !is_valid_app_id(&manifest.id) {
+            problems.push(format!("Invalid application ID: {}", manifest.id));
+        }
+        if let Err(e) = manifest.normalize_refs() {
+            problems.push(e.to_string());
+        }
+        let manifest_dir = path.parent().unwrap_or(Path::new("."));
+        match manifest.resolved_modules(manifest_dir) {
+            Ok(modules) => problems.extend(missing_local_sources(&modules, manifest_dir)),
+            Err(e) => problems.push(e.to_string()),
+        }
+        Ok((manifest, problems))
+    }
+
     pub fn from_file(path: &Path) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let manifest: Manifest = match path.extension().and_then(|s| s.to_str()) {
-            Some("json") => serde_json::from_str(&content)?,
-            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
-            _ => return Err(anyhow::anyhow!("Unsupported manifest format")),
-        };
-        if !is_valid_dbus_name(&manifest.id) {
+        let mut manifest: Manifest = parse_manifest_file(path)?;
+        if !is_valid_app_id(&manifest.id) {
             return Err(anyhow::anyhow!("Invalid application ID: {}", manifest.id));
         }
+        manifest.normalize_refs()?;
         Ok(manifest)
     }
 }
 
-/// Recursively finds manifest files in the given path, optionally excluding a prefix subtree.
-/// Returns a sorted Vec of manifest file paths, prioritizing ".Devel." manifests and shallower paths.
-pub fn find_manifests_in_path(path: &Path, exclude_prefix: Option<&Path>) -> Result<Vec<PathBuf>> {
-    use walkdir::WalkDir;
+/// Returns a problem for every local source `path`/`paths` of `modules` that doesn't exist
+/// relative to `manifest_dir`.
+fn missing_local_sources(modules: &[Module], manifest_dir: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    for module in modules {
+        let Module::Object { name, sources, .. } = module else {
+            continue;
+        };
+        for source in sources {
+            let paths = source["path"].as_str().into_iter().chain(
+                source["paths"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|p| p.as_str()),
+            );
+            for path in paths.filter(|path| !manifest_dir.join(path).exists()) {
+                problems.push(format!("Module {name}: local source {path:?} not found"));
+            }
+        }
+    }
+    problems
+}
+
+/// Splits a runtime reference like `org.gnome.Platform//46` or `org.gnome.Platform/x86_64/46`
+/// into its ID and branch. A bare ID has no branch.
+fn split_ref(reference: &str) -> (String, Option<String>) {
+    let mut parts = reference.splitn(3, '/');
+    let id = parts.next().unwrap_or_default().to_string();
+    let branch = parts.nth(1).filter(|branch| !branch.is_empty());
+    (id, branch.map(str::to_string))
+}
 
-    let mut manifests = vec![];
+/// Directories that usually hold vendored projects, whose manifests aren't the project's own.
+pub const DEFAULT_MANIFEST_IGNORE: [&str; 5] = [
+    "subprojects",
+    "third_party",
+    "third-party",
+    "vendor",
+    "node_modules",
+];
+
+/// Returns true if `relative_path` matches an ignore `pattern`. Like in `.gitignore`, a pattern
+/// such as `libs/*` matches at any depth, comparing it against as many trailing components.
+fn matches_ignore_pattern(pattern: &str, relative_path: &Path) -> bool {
+    let pattern = pattern.trim_matches('/');
+    let components: Vec<&str> = relative_path
+        .iter()
+        .filter_map(|component| component.to_str())
+        .collect();
+    let count = pattern.split('/').count();
+    count <= components.len()
+        && glob_match(pattern, &components[components.len() - count..].join("/"))
+}
+
+/// Recursively collects the files under `path` that could be manifests, optionally excluding
+/// a prefix subtree. Hidden files and directories, and those matching `ignore`, are skipped.
+fn find_manifest_candidates(
+    path: &Path,
+    exclude_prefix: Option<&Path>,
+    ignore: &[String],
+) -> Vec<PathBuf> {
+    use walkdir::WalkDir;
 
     let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
     let exclude_prefix =
         exclude_prefix.map(|p| p.canonicalize().unwrap_or_else(|_| p.to_path_buf()));
 
-    for entry in WalkDir::new(&path)
+    // Symlinked directories are walked too, so a manifest can be found through several paths.
+    // Loops are reported as errors by walkdir and skipped.
+    WalkDir::new(&path)
+        .follow_links(true)
         .into_iter()
         .filter_entry(|e| {
             if e.depth() == 0 {
@@ -100,33 +931,1004 @@ pub fn find_manifests_in_path(path: &Path, exclude_prefix: Option<&Path>) -> Res
             if e.file_name().to_str().is_some_and(|s| s.starts_with('.')) {
                 return false;
             }
-            if let Some(prefix) = &exclude_prefix {
-                if e.path().starts_with(prefix) {
-                    return false;
-                }
+            if let Some(prefix) = &exclude_prefix
+                && e.path().starts_with(prefix)
+            {
+                return false;
             }
-            true
+            let relative_path = e.path().strip_prefix(&path).unwrap_or(e.path());
+            !ignore
+                .iter()
+                .any(|pattern| matches_ignore_pattern(pattern, relative_path))
         })
         .filter_map(|e| e.ok())
         .filter(|e| e.file_type().is_file())
-        .filter(|e| {
-            matches!(
-                e.path().extension().and_then(|s| s.to_str()),
-                Some("json") | Some("yaml") | Some("yml")
-            )
-        })
-        .filter(|e| Manifest::from_file(e.path()).is_ok())
-    {
-        manifests.push(entry.into_path());
-    }
+        .filter(|e| has_manifest_extension(e.path()))
+        .map(|e| e.into_path())
+        .collect()
+}
 
-    manifests.sort_by(|a, b| {
+/// Sorts manifests by depth, then ".Devel." ones first among manifests at the same depth,
+/// then by path so the order doesn't depend on the walk or on parsing order.
+fn sort_manifests(manifests: &mut [(PathBuf, Manifest)]) {
+    manifests.sort_by(|(a, _), (b, _)| {
         let a_is_devel = a.to_str().unwrap().contains(".Devel.");
         let b_is_devel = b.to_str().unwrap().contains(".Devel.");
-        b_is_devel
-            .cmp(&a_is_devel)
-            .then_with(|| a.components().count().cmp(&b.components().count()))
+        a.components()
+            .count()
+            .cmp(&b.components().count())
+            .then_with(|| b_is_devel.cmp(&a_is_devel))
+            .then_with(|| a.cmp(b))
     });
+}
+
+/// Parses the candidate files, split in chunks over up to `threads` threads, as parsing
+/// dominates discovery in large trees. Files that aren't manifests are left out.
+fn parse_manifest_candidates(candidates: Vec<PathBuf>, threads: usize) -> Vec<(PathBuf, Manifest)> {
+    let parse = |paths: &[PathBuf]| -> Vec<(PathBuf, Manifest)> {
+        paths
+            .iter()
+            .filter_map(|path| Some((path.clone(), Manifest::from_file(path).ok()?)))
+            .collect()
+    };
+    if threads < 2 || candidates.len() < 2 {
+        return parse(&candidates);
+    }
+
+    let chunk_size = candidates.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = candidates
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || parse(chunk)))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Recursively finds manifest files in the given path, optionally excluding a prefix subtree
+/// and paths matching the `ignore` globs.
+/// Returns a sorted Vec of manifest file paths, prioritizing shallower paths and, among manifests
+/// at the same depth, ".Devel." ones.
+/// Each path is returned along with its parsed manifest, so callers don't need to parse it again.
+pub fn find_manifests_in_path(
+    path: &Path,
+    exclude_prefix: Option<&Path>,
+    ignore: &[String],
+) -> Result<Vec<(PathBuf, Manifest)>> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut manifests = parse_manifest_candidates(
+        find_manifest_candidates(path, exclude_prefix, ignore),
+        threads,
+    );
+
+    sort_manifests(&mut manifests);
 
     Ok(manifests)
 }
+
+/// Finds manifests under `current_dir` first, then the rest of `base_dir`.
+/// Manifests reachable from both walks are only returned once, keeping the first occurrence.
+/// Vendored directories are skipped, along with paths matching the `extra_ignore` globs.
+pub fn find_manifests(
+    current_dir: &Path,
+    base_dir: &Path,
+    extra_ignore: &[String],
+) -> Result<Vec<(PathBuf, Manifest)>> {
+    let current_dir_canon = current_dir.canonicalize()?;
+    let base_dir_canon = base_dir.canonicalize()?;
+    let ignore: Vec<String> = DEFAULT_MANIFEST_IGNORE
+        .iter()
+        .map(|s| s.to_string())
+        .chain(extra_ignore.iter().cloned())
+        .collect();
+
+    // Walking the canonical dirs keeps the exclusion working when only one of them goes
+    // through a symlink.
+    let mut manifests = find_manifests_in_path(&current_dir_canon, None, &ignore)?;
+    if current_dir_canon != base_dir_canon {
+        manifests.extend(find_manifests_in_path(
+            &base_dir_canon,
+            Some(&current_dir_canon),
+            &ignore,
+        )?);
+    }
+
+    let mut seen = HashSet::new();
+    manifests.retain(|(path, _)| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+    Ok(manifests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_manifest(dir: &Path, relative_path: &str, id: &str) -> PathBuf {
+        let path = dir.join(relative_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            format!(
+                r#"{{
+                    "id": "{id}",
+                    "sdk": "org.gnome.Sdk",
+                    "runtime": "org.gnome.Platform",
+                    "runtime-version": "48",
+                    "command": "example"
+                }}"#
+            ),
+        )
+        .unwrap();
+        path.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn shallower_manifest_wins_over_nested_devel() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write_manifest(dir.path(), "com.example.json", "com.example.App");
+        let nested = write_manifest(
+            dir.path(),
+            "subproject/com.example.Devel.json",
+            "com.example.Devel",
+        );
+
+        let manifests = find_manifests_in_path(dir.path(), None, &[]).unwrap();
+        let paths: Vec<PathBuf> = manifests.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![root, nested]);
+    }
+
+    #[test]
+    fn devel_manifest_wins_at_equal_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let release = write_manifest(dir.path(), "com.example.json", "com.example.App");
+        let devel = write_manifest(dir.path(), "com.example.Devel.json", "com.example.Devel");
+
+        let manifests = find_manifests_in_path(dir.path(), None, &[]).unwrap();
+        let paths: Vec<PathBuf> = manifests.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![devel, release]);
+    }
+
+    #[test]
+    fn manifests_reachable_from_both_roots_are_deduplicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().join("project");
+        let manifest = write_manifest(&base_dir, "app/com.example.App.json", "com.example.App");
+        let other = write_manifest(&base_dir, "com.example.Other.json", "com.example.Other");
+        // The base dir walk finds the app manifest again, through the symlink.
+        std::os::unix::fs::symlink(base_dir.join("app"), base_dir.join("packaging")).unwrap();
+
+        let manifests = find_manifests(&base_dir.join("app"), &base_dir, &[]).unwrap();
+        let paths: Vec<PathBuf> = manifests.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![manifest, other]);
+    }
+
+    #[test]
+    fn symlinked_base_dir_is_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let app = write_manifest(&root, "app/com.example.App.json", "com.example.App");
+        let other = write_manifest(&root, "com.example.Other.json", "com.example.Other");
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+
+        let manifests = find_manifests(&root.join("app"), &link, &[]).unwrap();
+        let paths: Vec<PathBuf> = manifests.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![app, other]);
+    }
+
+    #[test]
+    fn vendored_manifests_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = write_manifest(dir.path(), "com.example.json", "com.example.App");
+        write_manifest(
+            dir.path(),
+            "subprojects/libfoo/org.foo.Foo.json",
+            "org.foo.Foo",
+        );
+        write_manifest(dir.path(), "libs/bar/org.bar.Bar.json", "org.bar.Bar");
+        let kept = write_manifest(dir.path(), "build-aux/org.baz.Baz.json", "org.baz.Baz");
+
+        let manifests = find_manifests(dir.path(), dir.path(), &["libs/*".to_string()]).unwrap();
+        let paths: Vec<PathBuf> = manifests.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec![manifest, kept]);
+
+        assert!(matches_ignore_pattern("vendor", Path::new("a/vendor")));
+        assert!(matches_ignore_pattern("/libs/*/", Path::new("x/libs/bar")));
+        assert!(!matches_ignore_pattern("libs/*", Path::new("libs")));
+    }
+
+    #[test]
+    fn commented_json_manifest_parses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.json");
+        fs::write(
+            &path,
+            r#"{
+                // The application ID
+                "id": "com.example.App",
+                "sdk": "org.gnome.Sdk", /* trailing block comment */
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "48",
+                /*
+                 * Multi-line block comment
+                 */
+                "command": "example",
+                "finish-args": ["--filesystem=/tmp//scratch", "--env=URL=http://example.com/*"]
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.id, "com.example.App");
+        assert_eq!(
+            manifest.finish_args,
+            vec![
+                "--filesystem=/tmp//scratch",
+                "--env=URL=http://example.com/*"
+            ]
+        );
+    }
+
+    fn module_with_buildsystem(buildsystem: Option<&str>) -> Module {
+        Module::Object {
+            name: "example".to_string(),
+            buildsystem: buildsystem.map(str::to_string),
+            config_opts: None,
+            build_commands: None,
+            post_install: None,
+            build_options: None,
+            independent: false,
+            sources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn buildsystem_dispatch() {
+        let cases = [
+            (Some("meson"), BuildSystem::Meson),
+            (Some("cmake"), BuildSystem::CMake),
+            (Some("cmake-ninja"), BuildSystem::CMake),
+            (Some("simple"), BuildSystem::Simple),
+            (Some("autotools"), BuildSystem::Autotools),
+            (None, BuildSystem::Autotools),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(
+                module_with_buildsystem(name).buildsystem(),
+                Some(expected),
+                "buildsystem {name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn reference_module_has_no_buildsystem() {
+        let module = Module::Reference("shared-modules/foo.json".to_string());
+        assert_eq!(module.buildsystem(), None);
+    }
+
+    #[test]
+    fn cli_run_args_follow_x_run_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yaml");
+        fs::write(
+            &path,
+            "id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             command: example\n\
+             x-run-args: ['--verbose']\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.run_command_args(&["--foo".to_string()]).unwrap(),
+            vec!["example", "--verbose", "--foo"]
+        );
+    }
+
+    #[test]
+    fn file_placeholders_take_the_cli_args() {
+        let run_args: Vec<String> = ["--verbose", "@@u", "%U", "@@", "--new-window"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            expand_file_placeholders(&run_args, &[]),
+            vec!["--verbose", "--new-window"]
+        );
+        assert_eq!(
+            expand_file_placeholders(&run_args, &["a.txt".to_string(), "b.txt".to_string()]),
+            vec!["--verbose", "a.txt", "b.txt", "--new-window"]
+        );
+        // An unterminated placeholder runs to the end.
+        assert_eq!(
+            expand_file_placeholders(&run_args[..3], &["a.txt".to_string()]),
+            vec!["--verbose", "a.txt"]
+        );
+    }
+
+    #[test]
+    fn check_reports_every_problem() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("present.patch"), "").unwrap();
+        let path = dir.path().join("example.json");
+        fs::write(
+            &path,
+            r#"{
+                "id": "example",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "modules": [
+                    {
+                        "name": "libfoo",
+                        "sources": [
+                            { "type": "archive", "url": "https://example.com/foo.tar.xz" },
+                            { "type": "patch", "paths": ["present.patch", "missing.patch"] }
+                        ]
+                    },
+                    { "name": "example", "sources": [{ "type": "dir", "path": "app" }] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let (_, problems) = Manifest::check_file(&path).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                "Invalid application ID: example",
+                "Missing runtime-version, and no branch in the runtime or SDK",
+                "Module libfoo: local source \"missing.patch\" not found",
+                "Module example: local source \"app\" not found",
+            ]
+        );
+
+        fs::create_dir(dir.path().join("app")).unwrap();
+        fs::write(dir.path().join("missing.patch"), "").unwrap();
+        let valid = fs::read_to_string(&path)
+            .unwrap()
+            .replace(r#""id": "example""#, r#""id": "com.example.App""#)
+            .replace(
+                r#""runtime": "org.gnome.Platform""#,
+                r#""runtime": "org.gnome.Platform//48""#,
+            );
+        fs::write(&path, valid).unwrap();
+        let (_, problems) = Manifest::check_file(&path).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+    }
+
+    #[test]
+    fn builder_manifest_filters_modules() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("libbar.json"), r#"{ "name": "libbar" }"#).unwrap();
+        let path = dir.path().join("com.example.App.json");
+        fs::write(
+            &path,
+            r#"{
+                "id": "com.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform//48",
+                "modules": [
+                    { "name": "libfoo", "x-flatplay-independent": true },
+                    "libbar.json",
+                    { "name": "example" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let module_names = |builder_manifest: &BuilderManifest| -> Vec<String> {
+            let value: serde_json::Value = parse_manifest_file(builder_manifest.path()).unwrap();
+            value["modules"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|module| module_value_name(module, dir.path()).unwrap())
+                .collect()
+        };
+
+        let excluded = ["libfoo".to_string(), "libbar".to_string()];
+        let except =
+            filtered_builder_manifest(&path, Some(ModuleFilter::Except(&excluded))).unwrap();
+        assert_eq!(module_names(&except), ["example"]);
+        let only = filtered_builder_manifest(&path, Some(ModuleFilter::Only("libfoo"))).unwrap();
+        assert_eq!(module_names(&only), ["libfoo"]);
+        assert_ne!(except.path(), only.path());
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        let modules = manifest.resolved_modules(dir.path()).unwrap();
+        let independent: Vec<bool> = modules.iter().map(Module::is_independent).collect();
+        assert_eq!(independent, [true, false, false]);
+    }
+
+    #[test]
+    fn yaml_merge_keys_are_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.App.yml");
+        fs::write(
+            &path,
+            "id: com.example.App\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             sdk: org.gnome.Sdk\n\
+             x-defaults: &defaults\n  \
+               buildsystem: meson\n  \
+               config-opts: [-Dtests=false]\n\
+             modules:\n  \
+               - <<: *defaults\n    \
+                 name: libfoo\n  \
+               - <<: *defaults\n    \
+                 name: example\n    \
+                 config-opts: [-Dprofile=devel]\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        let modules = manifest.resolved_modules(dir.path()).unwrap();
+        let modules = serde_json::to_value(&modules).unwrap();
+        assert_eq!(modules[0]["name"], "libfoo");
+        assert_eq!(modules[0]["buildsystem"], "meson");
+        assert_eq!(
+            modules[0]["config-opts"],
+            serde_json::json!(["-Dtests=false"])
+        );
+        assert_eq!(modules[1]["name"], "example");
+        assert_eq!(modules[1]["buildsystem"], "meson");
+        assert_eq!(
+            modules[1]["config-opts"],
+            serde_json::json!(["-Dprofile=devel"])
+        );
+        assert_eq!(
+            find_manifests_in_path(dir.path(), None, &[]).unwrap().len(),
+            1
+        );
+
+        // flatpak-builder gets a copy with the merge keys resolved.
+        let builder_manifest = builder_manifest(&path).unwrap();
+        assert_ne!(builder_manifest.path(), path);
+        let content = fs::read_to_string(builder_manifest.path()).unwrap();
+        assert!(!content.contains("<<"));
+    }
+
+    #[test]
+    fn manifest_without_command_parses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.Extension.yml");
+        fs::write(
+            &path,
+            "id: com.example.App.Extension\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert!(manifest.command.is_none());
+        let err = manifest.run_command_args(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "The manifest declares no command to run");
+    }
+
+    #[test]
+    fn extension_and_extra_data_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.json");
+        fs::write(
+            &path,
+            r#"{
+                "id": "com.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "48",
+                "command": "example",
+                "add-extensions": {
+                    "com.example.Plugin": {
+                        "directory": "extensions",
+                        "subdirectories": true,
+                        "no-autodownload": true
+                    }
+                },
+                "modules": [{
+                    "name": "example",
+                    "sources": [{
+                        "type": "extra-data",
+                        "filename": "data.tar.gz",
+                        "url": "https://example.com/data.tar.gz",
+                        "sha256": "abc123",
+                        "size": 1024,
+                        "installed-size": 4096
+                    }]
+                }, "modules/fonts.json"]
+            }"#,
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("modules")).unwrap();
+        fs::write(
+            dir.path().join("modules/fonts.json"),
+            r#"{
+                "name": "fonts",
+                "sources": [{
+                    "type": "extra-data",
+                    "filename": "fonts.zip",
+                    "url": "https://example.com/fonts.zip",
+                    "sha256": "def456",
+                    "size": 2048
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.extension_args(),
+            vec![
+                "--extension=com.example.Plugin=directory=extensions",
+                "--extension=com.example.Plugin=no-autodownload=true",
+                "--extension=com.example.Plugin=subdirectories=true",
+            ]
+        );
+        assert_eq!(
+            manifest.extension_metadata(),
+            "\n[Extension com.example.Plugin]\n\
+             directory=extensions\n\
+             no-autodownload=true\n\
+             subdirectories=true\n"
+        );
+        assert_eq!(
+            manifest.extra_data_args(dir.path()).unwrap(),
+            vec![
+                "--extra-data=data.tar.gz:abc123:1024:4096:https://example.com/data.tar.gz",
+                "--extra-data=fonts.zip:def456:2048:0:https://example.com/fonts.zip",
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_metadata_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             default-branch: beta\n\
+             tags: [devel]\n\
+             command: example\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.default_branch.as_deref(), Some("beta"));
+        assert_eq!(
+            manifest.metadata_args(),
+            vec!["--metadata=Application=tags=devel;"]
+        );
+    }
+
+    #[test]
+    fn base_app_build_init_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.freedesktop.Sdk\n\
+             runtime: org.freedesktop.Platform\n\
+             runtime-version: '24.08'\n\
+             base: org.electronjs.Electron2.BaseApp\n\
+             base-version: '24.08'\n\
+             command: example\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.build_init_args(),
+            vec![
+                "--base=org.electronjs.Electron2.BaseApp",
+                "--base-version=24.08"
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_runtime_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        let write = |runtime_lines: &str| {
+            fs::write(
+                &path,
+                format!("app-id: com.example.App\n{runtime_lines}command: example\n"),
+            )
+            .unwrap();
+        };
+
+        write("sdk: org.gnome.Sdk//46\nruntime: org.gnome.Platform//46\n");
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.runtime, "org.gnome.Platform");
+        assert_eq!(manifest.sdk, "org.gnome.Sdk");
+        assert_eq!(manifest.runtime_version, "46");
+
+        write("sdk: org.gnome.Sdk\nruntime: org.gnome.Platform\nruntime-version: '47'\n");
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.runtime, "org.gnome.Platform");
+        assert_eq!(manifest.runtime_version, "47");
+
+        write("sdk: org.gnome.Sdk/x86_64/46\nruntime: org.gnome.Platform\nruntime-version: '47'\n");
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.sdk, "org.gnome.Sdk");
+        assert_eq!(manifest.runtime_version, "47");
+
+        write("sdk: org.gnome.Sdk\nruntime: org.gnome.Platform\n");
+        assert!(Manifest::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn separate_locales_defaults_to_true() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        let manifest = "app-id: com.example.App\n\
+                        sdk: org.gnome.Sdk\n\
+                        runtime: org.gnome.Platform\n\
+                        runtime-version: '48'\n";
+        fs::write(&path, manifest).unwrap();
+        assert!(Manifest::from_file(&path).unwrap().separate_locales);
+
+        fs::write(&path, format!("{manifest}separate-locales: false\n")).unwrap();
+        assert!(!Manifest::from_file(&path).unwrap().separate_locales);
+    }
+
+    #[test]
+    fn runtime_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("org.example.Platform.yml");
+        fs::write(
+            &path,
+            "id: org.example.Platform\n\
+             sdk: org.freedesktop.Sdk\n\
+             runtime: org.freedesktop.Platform\n\
+             runtime-version: '24.08'\n\
+             build-runtime: true\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert!(manifest.build_runtime);
+        assert_eq!(manifest.install_prefix(), "/usr");
+        assert_eq!(
+            manifest.build_init_args(),
+            vec!["--type=runtime", "--writable-sdk"]
+        );
+    }
+
+    #[test]
+    fn arch_build_options_merge_over_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             command: example\n\
+             build-options:\n\
+             \x20 cflags: -O2\n\
+             \x20 env:\n\
+             \x20   MODE: base\n\
+             \x20 arch:\n\
+             \x20   aarch64:\n\
+             \x20     cflags: -mcpu=cortex-a72\n\
+             \x20     env:\n\
+             \x20       MODE: arm\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        let aarch64 = manifest.build_options.for_arch("aarch64");
+        assert_eq!(aarch64.cflags.as_deref(), Some("-O2 -mcpu=cortex-a72"));
+        assert_eq!(
+            aarch64.env_args(),
+            vec!["--env=CFLAGS=-O2 -mcpu=cortex-a72", "--env=MODE=arm"]
+        );
+
+        let x86_64 = manifest.build_options.for_arch("x86_64");
+        assert_eq!(
+            x86_64.env_args(),
+            vec!["--env=CFLAGS=-O2", "--env=MODE=base"]
+        );
+    }
+
+    #[test]
+    fn scalar_env_values_are_read_as_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             command: example\n\
+             build-options:\n\
+             \x20 env:\n\
+             \x20   JOBS: 4\n\
+             \x20   VERBOSE: true\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.build_options.env_args(),
+            vec!["--env=JOBS=4", "--env=VERBOSE=true"]
+        );
+        assert_eq!(
+            find_manifests_in_path(dir.path(), None, &[]).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn yaml_document_markers_are_tolerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "---\n\
+             ---\n\
+             app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             command: example\n\
+             ---\n\
+             unrelated: document\n\
+             ---\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(manifest.id, "com.example.App");
+    }
+
+    #[test]
+    fn finish_args_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             finish-args:\n\
+             \x20 - --share=network\n\
+             \x20 - --socket=wayland\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.finish_args,
+            vec!["--share=network", "--socket=wayland"]
+        );
+    }
+
+    #[test]
+    fn finish_args_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.gnome.Sdk\n\
+             runtime: org.gnome.Platform\n\
+             runtime-version: '48'\n\
+             finish-args:\n\
+             \x20 share: [network, ipc]\n\
+             \x20 socket: wayland\n\
+             \x20 die-with-parent: true\n\
+             \x20 persist: false\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        let expected = vec![
+            "--die-with-parent",
+            "--share=network",
+            "--share=ipc",
+            "--socket=wayland",
+        ];
+        assert_eq!(manifest.finish_args, expected);
+
+        // flatpak-builder only reads the list form, so it gets a normalized copy.
+        let builder_manifest = builder_manifest(&path).unwrap();
+        assert_ne!(builder_manifest.path(), path);
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(builder_manifest.path()).unwrap()).unwrap();
+        assert_eq!(value["finish-args"], serde_json::json!(expected));
+    }
+
+    #[test]
+    fn sdk_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com.example.yml");
+        fs::write(
+            &path,
+            "app-id: com.example.App\n\
+             sdk: org.freedesktop.Sdk\n\
+             runtime: org.freedesktop.Platform\n\
+             runtime-version: '24.08'\n\
+             sdk-extensions:\n\
+             \x20 - org.freedesktop.Sdk.Extension.rust-stable\n\
+             command: example\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        assert_eq!(
+            manifest.build_init_args(),
+            vec!["--sdk-extension=org.freedesktop.Sdk.Extension.rust-stable"]
+        );
+        assert_eq!(
+            manifest.sdk_extension_bin_dirs(),
+            vec!["/usr/lib/sdk/rust-stable/bin"]
+        );
+    }
+
+    #[test]
+    fn module_includes_are_expanded() {
+        let dir = tempfile::tempdir().unwrap();
+        let modules_dir = dir.path().join("modules");
+        fs::create_dir_all(&modules_dir).unwrap();
+        for name in ["zlib", "libfoo"] {
+            fs::write(
+                modules_dir.join(format!("{name}.json")),
+                format!(
+                    r#"{{"name": "{name}", "sources": [{{"type": "dir", "path": "{name}"}}]}}"#
+                ),
+            )
+            .unwrap();
+        }
+        fs::write(modules_dir.join("libbar.yml"), "name: libbar\n").unwrap();
+        fs::write(modules_dir.join("README.md"), "").unwrap();
+        let path = dir.path().join("com.example.json");
+        fs::write(
+            &path,
+            r#"{
+                "id": "com.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "48",
+                "command": "example",
+                "modules": ["modules/", "modules/lib*.json", {"name": "example"}]
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = Manifest::from_file(&path).unwrap();
+        let modules = manifest.resolved_modules(dir.path()).unwrap();
+        let names: Vec<&str> = modules.iter().map(Module::name).collect();
+        assert_eq!(names, vec!["libbar", "libfoo", "zlib", "libfoo", "example"]);
+        let Module::Object { sources, .. } = &modules[1] else {
+            panic!("module was not resolved");
+        };
+        assert_eq!(sources[0]["path"], "modules/libfoo");
+
+        let builder_path = {
+            let builder = builder_manifest(&path).unwrap();
+            let expanded: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(builder.path()).unwrap()).unwrap();
+            assert_eq!(
+                expanded["modules"],
+                serde_json::json!([
+                    "modules/libbar.yml",
+                    "modules/libfoo.json",
+                    "modules/zlib.json",
+                    "modules/libfoo.json",
+                    {"name": "example"}
+                ])
+            );
+            builder.path().to_path_buf()
+        };
+        assert!(!builder_path.exists());
+
+        assert!(expand_module_reference("modules/*.yaml", dir.path()).is_err());
+        assert!(expand_module_reference("mod*/libfoo.json", dir.path()).is_err());
+    }
+
+    #[test]
+    fn remote_manifest_urls() {
+        assert!(is_manifest_url("https://example.com/com.example.App.json"));
+        assert!(!is_manifest_url("build-aux/com.example.App.json"));
+        assert_eq!(
+            remote_manifest_extension("https://example.com/com.example.App.yml?raw=true"),
+            Some("yml")
+        );
+        assert_eq!(
+            remote_manifest_extension("https://example.com/com.example.App.json"),
+            Some("json")
+        );
+        assert_eq!(
+            remote_manifest_extension("https://example.com/manifest"),
+            None
+        );
+        assert_eq!(
+            remote_manifest_extension("https://example.com/app.txt"),
+            None
+        );
+    }
+
+    #[test]
+    fn app_id_validation() {
+        let valid = [
+            "org.gnome.Builder",
+            "org.gnome.Builder.Devel",
+            "com.github.tchx84.Flatseal",
+            "io.github._4ch.App",
+            "org.freedesktop.Platform.GL.nvidia-550-54-14",
+            "org.example.app2d",
+            "com.example.my_app",
+            "org.electronjs.Electron2.BaseApp",
+        ];
+        for id in valid {
+            assert!(is_valid_app_id(id), "{id} should be valid");
+        }
+
+        let invalid = [
+            "",
+            "com.example",
+            "com..example",
+            ".com.example.App",
+            "com.example.App.",
+            "com.example.2App",
+            "com.4example.App",
+            "com.my-org.App",
+            "com.example.App$",
+            "com.exämple.App",
+        ];
+        for id in invalid {
+            assert!(!is_valid_app_id(id), "{id} should be invalid");
+        }
+        assert!(!is_valid_app_id(&format!(
+            "com.example.{}",
+            "a".repeat(250)
+        )));
+    }
+
+    #[test]
+    fn parallel_discovery_matches_serial_parsing() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..60 {
+            write_manifest(
+                dir.path(),
+                &format!("sub{}/com.example.App{i}.json", i % 7),
+                &format!("com.example.App{i}"),
+            );
+            fs::write(dir.path().join(format!("data{i}.yml")), "not: a manifest").unwrap();
+        }
+
+        let mut serial: Vec<(PathBuf, Manifest)> = find_manifest_candidates(dir.path(), None, &[])
+            .into_iter()
+            .filter_map(|path| Some((path.clone(), Manifest::from_file(&path).ok()?)))
+            .collect();
+        sort_manifests(&mut serial);
+        let serial: Vec<PathBuf> = serial.into_iter().map(|(path, _)| path).collect();
+
+        for threads in [2, 3, 8] {
+            let mut parallel =
+                parse_manifest_candidates(find_manifest_candidates(dir.path(), None, &[]), threads);
+            sort_manifests(&mut parallel);
+            let parallel: Vec<PathBuf> = parallel.into_iter().map(|(path, _)| path).collect();
+
+            assert_eq!(parallel.len(), 60);
+            assert_eq!(parallel, serial);
+        }
+    }
+}
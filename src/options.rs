@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+
+/// Options that control how the application is run.
+#[derive(Args, Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Don't grant GPU access to the application
+    #[arg(long)]
+    pub no_device: bool,
+
+    /// Don't wire the accessibility bus into the sandbox
+    #[arg(long)]
+    pub no_a11y: bool,
+
+    /// Force the X11 display backend, hiding the Wayland socket
+    #[arg(long, conflicts_with = "wayland")]
+    pub x11: bool,
+
+    /// Force the Wayland display backend, hiding the X11 socket
+    #[arg(long)]
+    pub wayland: bool,
+
+    /// Run the application on a virtual X display with xvfb-run instead of the host's, and exit
+    /// with its exit code. For smoke tests in CI
+    #[arg(long, conflicts_with_all = ["x11", "wayland"])]
+    pub headless: bool,
+
+    /// Grant access to a filesystem path on top of the manifest's finish-args, can be repeated
+    #[arg(long, value_name = "SPEC")]
+    pub filesystem: Vec<String>,
+
+    /// Mount a host path read-only, can be repeated
+    #[arg(long, value_name = "PATH")]
+    pub ro_filesystem: Vec<String>,
+
+    /// Mount a host path read-write, can be repeated
+    #[arg(long, value_name = "PATH")]
+    pub rw_filesystem: Vec<String>,
+
+    /// Allow talking to a D-Bus name on top of the manifest's finish-args, can be repeated
+    #[arg(long, value_name = "NAME")]
+    pub talk_name: Vec<String>,
+
+    /// Expose a socket on top of the manifest's finish-args, can be repeated
+    #[arg(long, value_name = "SOCKET")]
+    pub socket: Vec<String>,
+
+    /// Start the application with empty data, config and cache directories, wiped on every run
+    #[arg(long, conflicts_with = "persist_data")]
+    pub clean_data: bool,
+
+    /// Keep the application's data, config and cache directories in DIR instead of ~/.var/app
+    #[arg(long, value_name = "DIR")]
+    pub persist_data: Option<PathBuf>,
+
+    /// Forward the whole host environment to the application, except variables like PATH and
+    /// LD_* that would break the sandbox. Only meant for debugging
+    #[arg(long)]
+    pub env_passthrough_all: bool,
+
+    /// Load extra environment variables for the application from a dotenv file
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Option<PathBuf>,
+
+    /// Run the application under a profiler, writing the capture to the build directory
+    #[arg(long, value_enum, value_name = "PROFILER", conflicts_with = "wrapper")]
+    pub profile: Option<Profiler>,
+
+    /// Run the application under this command, like `valgrind --tool=callgrind`. It is split into
+    /// arguments like a shell would, honouring quotes
+    #[arg(long, value_name = "COMMAND", allow_hyphen_values = true)]
+    pub wrapper: Option<String>,
+
+    /// Trace the system calls of the application and its children with strace, to stderr or to
+    /// FILE. Grants the sandbox ptrace access
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["profile", "wrapper"]
+    )]
+    pub strace: Option<Option<PathBuf>>,
+
+    /// Print the command used to run the application instead of running it
+    #[arg(long)]
+    pub print_run_command: bool,
+
+    /// Print the environment variables passed to the application as KEY=VALUE lines instead of
+    /// running it
+    #[arg(long, conflicts_with = "print_run_command")]
+    pub dump_env: bool,
+
+    /// Arguments passed to the application, after the manifest's `x-run-args` or in place of their
+    /// `@@`/`@@u` file placeholders
+    #[arg(last = true)]
+    pub args: Vec<String>,
+}
+
+/// Resolves a relative host path against the current directory, as flatpak only accepts
+/// absolute and home-relative paths. Paths starting with `~` are left to flatpak.
+fn host_path(path: &str) -> String {
+    if path.starts_with('~') {
+        return path.to_string();
+    }
+    std::path::absolute(path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+/// Returns the command `run --strace` launches the app under, following forks and writing
+/// the trace to `output` if given.
+pub fn strace_args(output: Option<&Path>) -> Vec<String> {
+    let mut args = vec!["strace".to_string(), "-f".to_string()];
+    if let Some(output) = output {
+        args.extend(["-o".to_string(), output.display().to_string()]);
+    }
+    args
+}
+
+/// Display backend forced on the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayBackend {
+    X11,
+    Wayland,
+}
+
+/// Profilers `run --profile` has presets for. Their tools must be in the SDK.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profiler {
+    Sysprof,
+    Perf,
+}
+
+impl Profiler {
+    /// Returns the file the capture is written to, in `dir`.
+    pub fn capture_file(self, dir: &Path) -> PathBuf {
+        match self {
+            Profiler::Sysprof => dir.join("capture.syscap"),
+            Profiler::Perf => dir.join("perf.data"),
+        }
+    }
+
+    /// Returns the command the application is launched under, writing the capture to `capture`.
+    pub fn wrapper_args(self, capture: &Path) -> Vec<String> {
+        let capture = capture.display().to_string();
+        let args = match self {
+            Profiler::Sysprof => vec!["sysprof-cli", "--force", &capture, "--"],
+            Profiler::Perf => vec!["perf", "record", "--call-graph=dwarf", "-o", &capture, "--"],
+        };
+        args.into_iter().map(str::to_string).collect()
+    }
+
+    /// Returns the sandbox permissions the profiler needs to write its capture to `dir`.
+    /// Access to `perf_event_open` comes with the `--allow=devel` every run gets.
+    pub fn permission_args(self, dir: &Path) -> Vec<String> {
+        let mut args = vec![format!("--filesystem={}", dir.display())];
+        match self {
+            // sysprofd collects the samples and is reached over the system bus.
+            Profiler::Sysprof => args.push("--system-talk-name=org.gnome.Sysprof3".to_string()),
+            // Hardware counters and symbol lookup for GPU drivers need the devices.
+            Profiler::Perf => args.push("--device=all".to_string()),
+        }
+        args
+    }
+}
+
+impl RunOptions {
+    /// Returns the permission arguments passed through from the command line.
+    pub fn permission_args(&self) -> Vec<String> {
+        let filesystems = self.filesystem.iter().map(|s| format!("--filesystem={s}"));
+        let ro_filesystems = self
+            .ro_filesystem
+            .iter()
+            .map(|s| format!("--filesystem={}:ro", host_path(s)));
+        let rw_filesystems = self
+            .rw_filesystem
+            .iter()
+            .map(|s| format!("--filesystem={}", host_path(s)));
+        let talk_names = self.talk_name.iter().map(|s| format!("--talk-name={s}"));
+        let sockets = self.socket.iter().map(|s| format!("--socket={s}"));
+        filesystems
+            .chain(ro_filesystems)
+            .chain(rw_filesystems)
+            .chain(talk_names)
+            .chain(sockets)
+            .collect()
+    }
+
+    /// Returns the forced display backend, or `None` to keep the manifest's sockets.
+    pub fn display_backend(&self) -> Option<DisplayBackend> {
+        if self.x11 || self.headless {
+            Some(DisplayBackend::X11)
+        } else if self.wayland {
+            Some(DisplayBackend::Wayland)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filesystem_mounts() {
+        let options = RunOptions {
+            filesystem: vec!["xdg-download".to_string()],
+            ro_filesystem: vec!["/srv/data".to_string(), "~/Pictures".to_string()],
+            rw_filesystem: vec!["fixtures".to_string()],
+            ..Default::default()
+        };
+        let fixtures = std::env::current_dir().unwrap().join("fixtures");
+        assert_eq!(
+            options.permission_args(),
+            vec![
+                "--filesystem=xdg-download".to_string(),
+                "--filesystem=/srv/data:ro".to_string(),
+                "--filesystem=~/Pictures:ro".to_string(),
+                format!("--filesystem={}", fixtures.display()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::IsTerminal;
+
+use anyhow::{Result, anyhow};
+use colored::*;
+
+use crate::command::{CommandRunner, flatpak_bin, run_command_with_timeout, set_flatpak_bin};
+use crate::config::Config;
+use crate::manifest::Manifest;
+use crate::utils::installation_arg;
+
+/// Returns the branch an SDK's `metadata` asks for extension `extension`, from the `version`
+/// or first of the `versions` of the `[Extension]` group it belongs to.
+fn extension_branch(metadata: &str, extension: &str) -> Option<String> {
+    let mut in_group = false;
+    let mut versions = None;
+    for line in metadata.lines().map(str::trim) {
+        if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if versions.is_some() {
+                break;
+            }
+            in_group = group.strip_prefix("Extension ").is_some_and(|point| {
+                extension == point || extension.starts_with(&format!("{point}."))
+            });
+        } else if in_group && let Some((key, value)) = line.split_once('=') {
+            match key.trim() {
+                "version" => return Some(value.trim().to_string()),
+                "versions" => versions = value.split(';').next().map(|v| v.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    versions.filter(|version| !version.is_empty())
+}
+
+/// Returns the refs of the runtime, SDK and SDK extensions of `manifest` that aren't installed.
+/// SDK extensions follow the branch of the base SDK, like `24.08` for `org.gnome.Sdk//48`, so
+/// they are looked up on the branch the SDK's metadata asks for. Until the SDK is installed
+/// that branch is unknown and they are left out.
+pub(crate) fn missing_runtimes(
+    manifest: &Manifest,
+    installation_arg: Option<&str>,
+    runner: &dyn CommandRunner,
+) -> Vec<String> {
+    let is_installed = |ref_: &str| {
+        let mut args = vec!["info"];
+        args.extend(installation_arg);
+        args.push(ref_);
+        runner.succeeds(flatpak_bin(), &args)
+    };
+    let sdk_ref = format!("{}//{}", manifest.sdk, manifest.runtime_version);
+    let mut missing: Vec<String> = [
+        format!("{}//{}", manifest.runtime, manifest.runtime_version),
+        sdk_ref.clone(),
+    ]
+    .into_iter()
+    .filter(|ref_| !is_installed(ref_))
+    .collect();
+    if manifest.sdk_extensions.is_empty() || missing.contains(&sdk_ref) {
+        return missing;
+    }
+
+    let mut args = vec!["info", "--show-metadata"];
+    args.extend(installation_arg);
+    args.push(&sdk_ref);
+    let metadata = runner.output(flatpak_bin(), &args).unwrap_or_default();
+    missing.extend(
+        manifest
+            .sdk_extensions
+            .iter()
+            .map(|id| match extension_branch(&metadata, id) {
+                Some(branch) => format!("{id}//{branch}"),
+                None => format!("{id}//{}", manifest.runtime_version),
+            })
+            .filter(|ref_| !is_installed(ref_)),
+    );
+    missing
+}
+
+/// Returns the `flatpak uninstall` arguments removing the runtimes no installed app uses.
+fn uninstall_unused_args(installation: Option<&str>, assume_yes: bool) -> Vec<String> {
+    let mut args = vec!["uninstall".to_string(), "--unused".to_string()];
+    args.extend(installation.map(installation_arg));
+    if assume_yes {
+        args.push("-y".to_string());
+    }
+    args
+}
+
+/// Uninstalls the runtimes and SDKs no installed app uses. flatpak lists them and asks for
+/// confirmation first, unless `--yes` is given.
+pub fn prune_runtimes(config: &Config) -> Result<()> {
+    set_flatpak_bin(config.flatpak_bin.clone());
+    if !config.non_interactive && !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Cannot ask for confirmation without a terminal. Pass `--yes` to uninstall without asking."
+        ));
+    }
+    println!(
+        "{} SDKs of projects that aren't installed as apps count as unused. `--install-deps` installs them again on the next build.",
+        "⚠".yellow()
+    );
+    let args = uninstall_unused_args(config.installation.as_deref(), config.non_interactive);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run_command_with_timeout(flatpak_bin(), &args, &[], None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_branch_follows_the_sdk_metadata() {
+        let metadata = "[Runtime]\nname=org.gnome.Sdk\n\n\
+            [Extension org.freedesktop.Sdk.Extension]\ndirectory=lib/sdk\n\
+            subdirectories=true\nversion=24.08\n\n\
+            [Extension org.gnome.Sdk.Docs]\nversions=48;master\n";
+        assert_eq!(
+            extension_branch(metadata, "org.freedesktop.Sdk.Extension.rust-stable").as_deref(),
+            Some("24.08")
+        );
+        assert_eq!(
+            extension_branch(metadata, "org.gnome.Sdk.Docs").as_deref(),
+            Some("48")
+        );
+        assert_eq!(
+            extension_branch(metadata, "org.kde.Sdk.Extension.foo"),
+            None
+        );
+    }
+
+    #[test]
+    fn uninstall_unused_args_follow_the_options() {
+        assert_eq!(
+            uninstall_unused_args(None, false),
+            ["uninstall", "--unused"]
+        );
+        assert_eq!(
+            uninstall_unused_args(Some("system"), true),
+            ["uninstall", "--unused", "--system", "-y"]
+        );
+    }
+}
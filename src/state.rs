@@ -1,20 +1,47 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::Result;
+use colored::*;
 use serde::{Deserialize, Serialize};
 
 const STATE_DIR: &str = ".flatplay";
 const STATE_FILE_NAME: &str = "state.json";
+const STATE_BACKUP_FILE_NAME: &str = "state.json.bak";
+
+/// Version of the state file format, bumped whenever a change needs a migration.
+/// Files from before versioning have no `version` field and count as version 0.
+pub const STATE_VERSION: u32 = 1;
+
+/// Names of the timed build steps, in the order they run.
+pub const UPDATE_DEPENDENCIES_STEP: &str = "update_dependencies";
+pub const BUILD_DEPENDENCIES_STEP: &str = "build_dependencies";
+pub const BUILD_APPLICATION_STEP: &str = "build_application";
+pub const BUILD_STEPS: [&str; 3] = [
+    UPDATE_DEPENDENCIES_STEP,
+    BUILD_DEPENDENCIES_STEP,
+    BUILD_APPLICATION_STEP,
+];
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
 pub struct State {
+    pub version: u32,
     pub active_manifest: Option<PathBuf>,
     pub dependencies_updated: bool,
     pub dependencies_built: bool,
     pub application_built: bool,
+    /// Target arch the build progress above is for, `None` for the host arch.
+    pub arch: Option<String>,
     pub process_group_id: Option<u32>,
+    /// Wall-clock duration of the last run of each build step, keyed by step name.
+    pub last_build_durations: BTreeMap<String, Duration>,
+    /// Input hashes of the dependency modules at their last successful build, keyed by name.
+    pub module_hashes: BTreeMap<String, String>,
+    /// Definition hash of the dependency modules when their sources were last downloaded.
+    pub dependencies_hash: Option<String>,
     #[serde(skip)]
     pub base_dir: PathBuf,
 }
@@ -22,11 +49,16 @@ pub struct State {
 impl Default for State {
     fn default() -> Self {
         Self {
+            version: STATE_VERSION,
             active_manifest: None,
             dependencies_updated: false,
             dependencies_built: false,
             application_built: false,
+            arch: None,
             process_group_id: None,
+            last_build_durations: BTreeMap::new(),
+            module_hashes: BTreeMap::new(),
+            dependencies_hash: None,
             base_dir: PathBuf::new(),
         }
     }
@@ -37,7 +69,10 @@ impl State {
         base_dir.join(STATE_DIR).join(STATE_FILE_NAME)
     }
 
+    /// Loads the state of the project in `base_dir`, which is canonicalized so every path
+    /// derived from it agrees, whether or not it was reached through a symlink.
     pub fn load(base_dir: PathBuf) -> Result<Self> {
+        let base_dir = base_dir.canonicalize().unwrap_or(base_dir);
         let state_file = Self::state_file_path(&base_dir);
         if !state_file.exists() {
             return Ok(State {
@@ -45,12 +80,60 @@ impl State {
                 ..Default::default()
             });
         }
-        let content = fs::read_to_string(state_file)?;
-        let mut state: State = serde_json::from_str(&content)?;
+        let content = fs::read_to_string(&state_file)?;
+        let parsed = serde_json::from_str::<serde_json::Value>(&content).and_then(|mut value| {
+            let version = Self::migrate(&mut value);
+            serde_json::from_value::<State>(value).map(|state| (state, version))
+        });
+        let mut state: State = match parsed {
+            Ok((state, version)) if version < STATE_VERSION => {
+                let state = State { base_dir, ..state };
+                state.save()?;
+                return Ok(state);
+            }
+            Ok((state, version)) => {
+                if version > STATE_VERSION {
+                    eprintln!(
+                        "{} The state file was written by a newer flatplay (format version {}). Progress it tracks may be lost.",
+                        "⚠".yellow(),
+                        version
+                    );
+                }
+                state
+            }
+            Err(e) => {
+                // A killed process can leave a truncated file behind, so start over instead of failing.
+                let backup_file = base_dir.join(STATE_DIR).join(STATE_BACKUP_FILE_NAME);
+                fs::rename(&state_file, &backup_file)?;
+                eprintln!(
+                    "{} Corrupt state file ({}). Backed it up to {:?} and starting fresh.",
+                    "⚠".yellow(),
+                    e,
+                    backup_file
+                );
+                State::default()
+            }
+        };
         state.base_dir = base_dir;
         Ok(state)
     }
 
+    /// Upgrades a state file from an older format in place, returning the version it had.
+    /// Newer formats are left alone, as their unknown fields are simply ignored.
+    fn migrate(value: &mut serde_json::Value) -> u32 {
+        let version = value["version"]
+            .as_u64()
+            .map_or(0, |v| u32::try_from(v).unwrap_or(u32::MAX));
+        if version >= STATE_VERSION {
+            return version;
+        }
+        // Version 0 only lacks fields that have defaults, so stamping the version is enough.
+        if let Some(object) = value.as_object_mut() {
+            object.insert("version".to_string(), STATE_VERSION.into());
+        }
+        version
+    }
+
     pub fn save(&self) -> Result<()> {
         let state_dir = self.base_dir.join(STATE_DIR);
         fs::create_dir_all(state_dir)?;
@@ -67,3 +150,70 @@ impl State {
         self.application_built = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_state_is_backed_up_and_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join(STATE_DIR);
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(
+            state_dir.join(STATE_FILE_NAME),
+            "{\"application_built\": tr",
+        )
+        .unwrap();
+
+        let state = State::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(state.base_dir, dir.path().canonicalize().unwrap());
+        assert!(!state.application_built);
+        assert!(state_dir.join(STATE_BACKUP_FILE_NAME).is_file());
+        assert!(!state_dir.join(STATE_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn symlinked_base_dir_is_canonicalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(&root).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+
+        let mut state = State::load(link).unwrap();
+        assert_eq!(state.base_dir, root.canonicalize().unwrap());
+        state.application_built = true;
+        state.save().unwrap();
+        assert!(root.join(STATE_DIR).join(STATE_FILE_NAME).is_file());
+    }
+
+    #[test]
+    fn old_state_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join(STATE_DIR);
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(
+            state_dir.join(STATE_FILE_NAME),
+            "{\"dependencies_updated\": true, \"application_built\": true}",
+        )
+        .unwrap();
+
+        let state = State::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.dependencies_updated && state.application_built);
+        assert!(state.module_hashes.is_empty());
+        let rewritten = fs::read_to_string(state_dir.join(STATE_FILE_NAME)).unwrap();
+        assert!(rewritten.contains(&format!("\"version\": {STATE_VERSION}")));
+
+        // Newer formats load what they can instead of failing.
+        fs::write(
+            state_dir.join(STATE_FILE_NAME),
+            "{\"version\": 99, \"application_built\": true, \"future_field\": 1}",
+        )
+        .unwrap();
+        let state = State::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(state.version, 99);
+        assert!(state.application_built);
+    }
+}
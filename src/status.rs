@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use colored::*;
+
+use crate::process::is_process_running;
+use crate::state::{BUILD_STEPS, State};
+use crate::utils::format_duration;
+
+/// Output formats of the `status` command.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatusFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn yes_no(value: bool) -> ColoredString {
+    if value { "yes".green() } else { "no".yellow() }
+}
+
+/// Prints the active manifest, the build progress and how long the last build steps took.
+pub fn print_status(state: &State) {
+    let manifest = state
+        .active_manifest
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "none".to_string());
+    println!("{} {}", "Active manifest:".bold(), manifest);
+    println!(
+        "{} {}",
+        "Dependencies updated:".bold(),
+        yes_no(state.dependencies_updated)
+    );
+    println!(
+        "{} {}",
+        "Dependencies built:".bold(),
+        yes_no(state.dependencies_built)
+    );
+    println!(
+        "{} {}",
+        "Application built:".bold(),
+        yes_no(state.application_built)
+    );
+
+    if state.last_build_durations.is_empty() {
+        return;
+    }
+    println!("{}", "Last build durations:".bold());
+    let mut total = Duration::ZERO;
+    for step in BUILD_STEPS {
+        if let Some(duration) = state.last_build_durations.get(step) {
+            total += *duration;
+            println!("  {:<20} {}", step, format_duration(*duration));
+        }
+    }
+    println!("  {:<20} {}", "total", format_duration(total).bold());
+}
+
+/// Returns the build progress and whether the last flatplay instance is still running, as a
+/// snapshot for editors and dashboards to poll.
+pub fn status_json(state: &State) -> serde_json::Value {
+    serde_json::json!({
+        "active_manifest": state.active_manifest,
+        "dependencies_updated": state.dependencies_updated,
+        "dependencies_built": state.dependencies_built,
+        "application_built": state.application_built,
+        "process_group_id": state.process_group_id,
+        "running": state.process_group_id.is_some_and(is_process_running),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_status() {
+        let state = State {
+            active_manifest: Some("/project/com.example.App.json".into()),
+            dependencies_updated: true,
+            process_group_id: Some(std::process::id()),
+            ..Default::default()
+        };
+        assert_eq!(
+            status_json(&state),
+            serde_json::json!({
+                "active_manifest": "/project/com.example.App.json",
+                "dependencies_updated": true,
+                "dependencies_built": false,
+                "application_built": false,
+                "process_group_id": std::process::id(),
+                "running": true,
+            })
+        );
+    }
+}
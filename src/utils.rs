@@ -1,9 +1,15 @@
+use anyhow::Result;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::Path;
 use std::process::Command;
+use std::time::Duration;
 
-pub fn get_host_env() -> HashMap<String, String> {
+use crate::cleanup::glob_match;
+use crate::options::DisplayBackend;
+
+pub fn get_host_env(extra_keys: &[String]) -> HashMap<String, String> {
     let forwarded_env_keys = [
         "COLORTERM",
         "DESKTOP_SESSION",
@@ -21,7 +27,7 @@ pub fn get_host_env() -> HashMap<String, String> {
     let mut env_vars = HashMap::new();
 
     for (key, value) in env::vars() {
-        if forwarded_env_keys.contains(&key.as_str()) {
+        if forwarded_env_keys.contains(&key.as_str()) || extra_keys.contains(&key) {
             env_vars.insert(key, value);
         }
     }
@@ -29,6 +35,298 @@ pub fn get_host_env() -> HashMap<String, String> {
     env_vars
 }
 
+/// Globs of host env vars `run --env-passthrough-all` never forwards, as they point at host
+/// paths or would override what flatpak sets up for the sandbox.
+const ENV_PASSTHROUGH_DENYLIST: [&str; 15] = [
+    "PATH",
+    "LD_*",
+    "HOME",
+    "PWD",
+    "OLDPWD",
+    "SHLVL",
+    "_",
+    "container",
+    "FLATPAK_*",
+    "DBUS_*",
+    "XDG_RUNTIME_DIR",
+    "XDG_*_DIRS",
+    "XDG_*_HOME",
+    "GI_TYPELIB_PATH",
+    "GIO_*",
+];
+
+/// Returns the `vars` forwarded by `run --env-passthrough-all`: all of them but the denylisted
+/// ones and those matching the `extra_deny` globs, sorted by name.
+pub fn passthrough_env(
+    vars: impl IntoIterator<Item = (String, String)>,
+    extra_deny: &[String],
+) -> Vec<(String, String)> {
+    let deny: Vec<&str> = ENV_PASSTHROUGH_DENYLIST
+        .into_iter()
+        .chain(extra_deny.iter().map(String::as_str))
+        .collect();
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(key, _)| !deny.iter().any(|pattern| glob_match(pattern, key)))
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// Parses dotenv-style `KEY=VALUE` lines, skipping blank lines and `#` comments.
+/// An optional `export ` prefix and matching surrounding quotes on the value are stripped.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(anyhow::anyhow!(
+                "Line {}: expected KEY=VALUE, got {:?}",
+                index + 1,
+                line
+            ));
+        };
+        let key = key.trim();
+        let is_valid_key = !key.is_empty()
+            && !key.starts_with(|c: char| c.is_ascii_digit())
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_valid_key {
+            return Err(anyhow::anyhow!(
+                "Line {}: invalid variable name {:?}",
+                index + 1,
+                key
+            ));
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|v| v.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Quotes an argument for a POSIX shell, leaving it as is when no quoting is needed.
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Splits a command line into arguments like a POSIX shell, honouring single and double quotes
+/// and backslash escapes. Nothing is expanded.
+pub fn shell_split(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                args.extend(current.take());
+            }
+            '\\' => {
+                let arg = current.get_or_insert_with(String::new);
+                arg.extend(chars.next());
+            }
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(anyhow::anyhow!("Unterminated ' in {line:?}")),
+                    }
+                }
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Inside double quotes, a backslash only escapes these.
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(anyhow::anyhow!("Unterminated \" in {line:?}")),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(anyhow::anyhow!("Unterminated \" in {line:?}")),
+                    }
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// Formats a duration for humans, e.g. `4.2s`, `3m 07s` or `1h 02m 03s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 3600 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds, e.g. `2024-05-01T12:30:00.250Z`.
+pub fn format_timestamp(time: std::time::SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, from Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Returns the total size in bytes of the files under `path`, without following symlinks.
+pub fn dir_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Formats a size in bytes for humans, e.g. `512 B`, `1.5 MiB` or `2.0 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Returns the sorted names of the executable files directly inside `dir`.
+pub fn list_executables(dir: &Path) -> Vec<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut executables: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    executables.sort();
+    executables
+}
+
+/// Removes repeated identical arguments, keeping the last occurrence of each.
+/// Keeping the last one preserves precedence, as flatpak applies permission flags in order.
+pub fn dedup_args(args: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut deduped: Vec<String> = args
+        .into_iter()
+        .rev()
+        .filter(|arg| seen.insert(arg.clone()))
+        .collect();
+    deduped.reverse();
+    deduped
+}
+
+/// Returns the arguments forcing a display backend. They must come after the manifest's
+/// finish-args, as flatpak applies socket flags in order.
+pub fn get_display_backend_args(backend: DisplayBackend) -> Vec<String> {
+    let args: &[&str] = match backend {
+        DisplayBackend::X11 => &[
+            "--nosocket=wayland",
+            "--socket=x11",
+            "--env=GDK_BACKEND=x11",
+            "--env=QT_QPA_PLATFORM=xcb",
+        ],
+        DisplayBackend::Wayland => &[
+            "--nosocket=x11",
+            "--nosocket=fallback-x11",
+            "--socket=wayland",
+            "--env=GDK_BACKEND=wayland",
+            "--env=QT_QPA_PLATFORM=wayland",
+        ],
+    };
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+/// Returns the device arguments needed for GPU acceleration.
+/// The NVIDIA proprietary driver needs more than the DRI nodes, so grant all devices for it.
+pub fn get_device_args() -> Vec<String> {
+    if Path::new("/proc/driver/nvidia/version").exists() || Path::new("/dev/nvidia0").exists() {
+        vec!["--device=dri".to_string(), "--device=all".to_string()]
+    } else {
+        vec!["--device=dri".to_string()]
+    }
+}
+
+/// XDG base directories of an app data dir and their subdirectory, laid out like ~/.var/app/<id>.
+pub const DATA_SUBDIRS: [&str; 4] = ["data", "config", "cache", ".local/state"];
+const DATA_DIR_ENV: [&str; 4] = [
+    "XDG_DATA_HOME",
+    "XDG_CONFIG_HOME",
+    "XDG_CACHE_HOME",
+    "XDG_STATE_HOME",
+];
+
+/// Returns the arguments exposing `data_dir` to the app and pointing its XDG base dirs into it.
+pub fn data_dir_args(data_dir: &Path) -> Vec<String> {
+    let mut args = vec![format!("--filesystem={}", data_dir.display())];
+    args.extend(
+        DATA_DIR_ENV
+            .iter()
+            .zip(DATA_SUBDIRS)
+            .map(|(key, subdir)| format!("--env={key}={}", data_dir.join(subdir).display())),
+    );
+    args
+}
+
 pub fn get_a11y_bus_args() -> Vec<String> {
     let output = Command::new("gdbus")
         .args([
@@ -68,3 +366,182 @@ pub fn get_a11y_bus_args() -> Vec<String> {
         },
     ]
 }
+
+/// Returns the flag selecting a flatpak installation: `user`, `system` or a custom one's name.
+pub fn installation_arg(installation: &str) -> String {
+    match installation {
+        "user" => "--user".to_string(),
+        "system" => "--system".to_string(),
+        name => format!("--installation={name}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passthrough_env_skips_the_denylist() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("LD_PRELOAD", "libfoo.so"),
+            ("XDG_DATA_DIRS", "/usr/share"),
+            ("XDG_CONFIG_HOME", "/home/user/.config"),
+            ("MY_TOKEN", "secret"),
+            ("RUST_LOG", "debug"),
+            ("GTK_DEBUG", "interactive"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let forwarded: Vec<String> = passthrough_env(vars, &["*_TOKEN".to_string()])
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(forwarded, ["GTK_DEBUG", "RUST_LOG"]);
+    }
+
+    #[test]
+    fn duplicate_permissions_are_removed() {
+        let args: Vec<String> = [
+            "--allow=devel",
+            "--talk-name=org.a11y.Bus",
+            "--socket=wayland",
+            "--nosocket=x11",
+            "--talk-name=org.a11y.Bus",
+            "--socket=x11",
+            "--nosocket=x11",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            dedup_args(args),
+            vec![
+                "--allow=devel",
+                "--socket=wayland",
+                "--talk-name=org.a11y.Bus",
+                "--socket=x11",
+                "--nosocket=x11",
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(
+            shell_quote("--env=LANG=en_US.UTF-8"),
+            "--env=LANG=en_US.UTF-8"
+        );
+        assert_eq!(
+            shell_quote("--talk-name=org.freedesktop.portal.*"),
+            "'--talk-name=org.freedesktop.portal.*'"
+        );
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn shell_splitting() {
+        assert_eq!(
+            shell_split("valgrind  --tool=callgrind").unwrap(),
+            ["valgrind", "--tool=callgrind"]
+        );
+        assert_eq!(
+            shell_split(r#"gdb -ex 'break main' --args"#).unwrap(),
+            ["gdb", "-ex", "break main", "--args"]
+        );
+        assert_eq!(
+            shell_split(r#"env "NAME=a \"b\"" x\ y '' "#).unwrap(),
+            ["env", "NAME=a \"b\"", "x y", ""]
+        );
+        assert!(shell_split("gdb -ex 'break main").is_err());
+    }
+
+    #[test]
+    fn env_file_parsing() {
+        let vars = parse_env_file(
+            "# Comment\n\nGTK_DEBUG=interactive\nexport G_MESSAGES_DEBUG=all\nGREETING=\"hello world\"\nEMPTY=\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("GTK_DEBUG".to_string(), "interactive".to_string()),
+                ("G_MESSAGES_DEBUG".to_string(), "all".to_string()),
+                ("GREETING".to_string(), "hello world".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+
+        let err = parse_env_file("A=1\n\nnot a variable\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 3:"));
+        let err = parse_env_file("1A=1\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 1:"));
+    }
+
+    #[test]
+    fn duration_formatting() {
+        assert_eq!(format_duration(Duration::from_millis(4200)), "4.2s");
+        assert_eq!(format_duration(Duration::from_secs(187)), "3m 07s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
+    }
+
+    #[test]
+    fn data_dir_env() {
+        assert_eq!(
+            data_dir_args(Path::new("/tmp/data")),
+            vec![
+                "--filesystem=/tmp/data",
+                "--env=XDG_DATA_HOME=/tmp/data/data",
+                "--env=XDG_CONFIG_HOME=/tmp/data/config",
+                "--env=XDG_CACHE_HOME=/tmp/data/cache",
+                "--env=XDG_STATE_HOME=/tmp/data/.local/state",
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_formatting() {
+        let time = |secs, millis| {
+            std::time::UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+        };
+        assert_eq!(format_timestamp(time(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(time(1709251200 + 45296, 250)),
+            "2024-03-01T12:34:56.250Z"
+        );
+        assert_eq!(
+            format_timestamp(time(951782400, 7)),
+            "2000-02-29T00:00:00.007Z"
+        );
+    }
+
+    #[test]
+    fn size_formatting() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+        std::fs::write(dir.path().join("a"), [0; 100]).unwrap();
+        std::fs::write(dir.path().join("subdir/b"), [0; 24]).unwrap();
+        assert_eq!(dir_size(dir.path()), 124);
+    }
+
+    #[test]
+    fn executables_are_listed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        for (name, mode) in [("zeta", 0o755), ("alpha", 0o700), ("data.txt", 0o644)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+
+        assert_eq!(list_executables(dir.path()), vec!["alpha", "zeta"]);
+        assert!(list_executables(&dir.path().join("missing")).is_empty());
+    }
+}